@@ -0,0 +1,156 @@
+//! Guides through the six-position accelerometer calibration
+//!
+//! Each pose is averaged once the board rests on the requested face, the fit runs after
+//! the last one.
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use bevy_mag::imu::{self, AccelCalibration, Stillness, POSES};
+use bevy_mag::math::FitError;
+use bevy_mag::{Calibration, SampleRead};
+
+/// Still readings averaged per pose
+const POSE_SAMPLES: usize = 100;
+
+#[derive(Resource)]
+struct AccelWizard {
+    /// Index into [`POSES`] being collected, `None` when idle
+    step: Option<usize>,
+    means: Vec<[f64; 3]>,
+    /// Still readings of the current pose
+    pose: Vec<[f32; 3]>,
+    stillness: Stillness,
+    still: bool,
+    error: Option<FitError>,
+}
+
+impl Default for AccelWizard {
+    fn default() -> Self {
+        AccelWizard {
+            step: None,
+            means: vec![],
+            pose: vec![],
+            // Only the accelerometer is averaged, the gyro threshold of the bias estimate
+            // does not apply
+            stillness: Stillness::new(50, 0.01, f64::INFINITY),
+            still: false,
+            error: None,
+        }
+    }
+}
+
+pub struct AccelWizardPlugin;
+
+impl Plugin for AccelWizardPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AccelWizard>()
+            .add_system(collect_poses)
+            .add_system(draw_accel_ui);
+    }
+}
+
+fn mean(readings: &[[f32; 3]]) -> [f64; 3] {
+    let n = readings.len() as f64;
+    readings
+        .iter()
+        .fold([0.0; 3], |s, a| {
+            [s[0] + a[0] as f64, s[1] + a[1] as f64, s[2] + a[2] as f64]
+        })
+        .map(|c| c / n)
+}
+
+fn collect_poses(
+    mut wizard: ResMut<AccelWizard>,
+    mut ev_samples: EventReader<SampleRead>,
+    mut calibration: ResMut<Calibration>,
+) {
+    for SampleRead { sample, .. } in ev_samples.iter() {
+        wizard.still = wizard.stillness.push(&sample.accel, &sample.gyro);
+        let Some(step) = wizard.step else {
+            continue;
+        };
+        if !wizard.still || imu::pose_of(&sample.accel) != Some(POSES[step]) {
+            wizard.pose.clear();
+            continue;
+        }
+        wizard.pose.push(sample.accel);
+        if wizard.pose.len() < POSE_SAMPLES {
+            continue;
+        }
+        let pose_mean = mean(&wizard.pose);
+        wizard.means.push(pose_mean);
+        wizard.pose.clear();
+        wizard.step = Some(step + 1).filter(|next| *next < POSES.len());
+        if wizard.step.is_some() {
+            continue;
+        }
+        let means: [[f64; 3]; 6] = wizard.means[..].try_into().expect("a mean per pose");
+        match AccelCalibration::fit(&means) {
+            Ok(accel) => {
+                calibration.accel = Some(accel);
+                wizard.error = None;
+            }
+            Err(e) => wizard.error = Some(e),
+        }
+    }
+}
+
+fn draw_accel_ui(
+    mut contexts: EguiContexts,
+    mut wizard: ResMut<AccelWizard>,
+    mut calibration: ResMut<Calibration>,
+) {
+    egui::Window::new("Accelerometer")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            match wizard.step {
+                Some(step) => {
+                    ui.label(format!(
+                        "Place the board {} ({}/{})",
+                        imu::pose_name(POSES[step]),
+                        step + 1,
+                        POSES.len()
+                    ));
+                    if wizard.still {
+                        ui.label("Still");
+                    } else {
+                        ui.colored_label(egui::Color32::YELLOW, "Moving");
+                    }
+                    ui.add(egui::ProgressBar::new(
+                        wizard.pose.len() as f32 / POSE_SAMPLES as f32,
+                    ));
+                    if ui.button("Cancel").clicked() {
+                        wizard.step = None;
+                    }
+                }
+                None => {
+                    if ui.button("Start six-position calibration").clicked() {
+                        wizard.step = Some(0);
+                        wizard.means.clear();
+                        wizard.pose.clear();
+                        wizard.error = None;
+                    }
+                }
+            }
+            if let Some(e) = &wizard.error {
+                ui.colored_label(egui::Color32::RED, format!("Calibration failed: {}", e));
+            }
+            if let Some(accel) = calibration.accel {
+                ui.separator();
+                ui.monospace(format!(
+                    "bias  {:10.4} {:10.4} {:10.4}",
+                    accel.bias[0], accel.bias[1], accel.bias[2]
+                ));
+                for (i, row) in accel.a.row_iter().enumerate() {
+                    let label = if i == 0 { "a    " } else { "     " };
+                    ui.monospace(format!(
+                        "{} {:10.4} {:10.4} {:10.4}",
+                        label, row[0], row[1], row[2]
+                    ));
+                }
+                if ui.button("Clear").clicked() {
+                    calibration.accel = None;
+                }
+            }
+        });
+}
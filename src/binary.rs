@@ -0,0 +1,349 @@
+//! Compact binary encoding of [`Sample`], the reference for firmware
+//!
+//! A frame is a message followed by its CRC, COBS encoded so it contains no zero bytes,
+//! and terminated by a zero byte. All values are little-endian.
+//!
+//! | Offset | Size | Field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 1    | message type, [`SAMPLE`]                      |
+//! | 1      | 1    | flags, [`HAS_SEQ`]                            |
+//! | 2      | 80   | `f32` `dt`, `accel`, `gyro`, `cal_mag`, `state`, `raw_mag` |
+//! | 82     | 4    | `u32` `seq`, only if [`HAS_SEQ`] is set       |
+//! | end    | 2    | CRC-16/CCITT-FALSE of everything before       |
+//!
+//! That is 86 to 90 bytes on the wire, about half of a JSON line.
+use std::fmt;
+
+use crate::Sample;
+
+/// Message type of a sample
+pub const SAMPLE: u8 = 0x01;
+
+/// Flag set if `seq` follows the floats
+pub const HAS_SEQ: u8 = 0x01;
+
+/// Frames end with this byte, which COBS removes from their contents
+pub const DELIMITER: u8 = 0x00;
+
+/// Floats in a sample, in the order they are written
+const FLOATS: usize = 1 + 3 + 3 + 3 + 7 + 3;
+
+const HEADER: usize = 2;
+const CRC: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Frame is not valid COBS
+    Cobs,
+    /// Checksum does not match the contents
+    Crc {
+        expected: u16,
+        got: u16,
+    },
+    UnknownMessage(u8),
+    Length {
+        expected: usize,
+        got: usize,
+    },
+    /// A value is NaN or infinite, which the JSON recording could not hold
+    NotFinite,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Cobs => write!(f, "invalid COBS encoding"),
+            DecodeError::Crc { expected, got } => {
+                write!(
+                    f,
+                    "CRC mismatch: expected {:04x}, got {:04x}",
+                    expected, got
+                )
+            }
+            DecodeError::UnknownMessage(t) => write!(f, "unknown message type {:02x}", t),
+            DecodeError::Length { expected, got } => {
+                write!(f, "wrong length: expected {} bytes, got {}", expected, got)
+            }
+            DecodeError::NotFinite => write!(f, "sample holds a value that is NaN or infinite"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xffff, no reflection
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Consistent overhead byte stuffing, the result contains no zero bytes
+pub fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len() + data.len() / 254 + 2);
+    let mut code_at = 0;
+    encoded.push(0);
+    let mut code = 1u8;
+    for (i, &byte) in data.iter().enumerate() {
+        if byte != 0 {
+            encoded.push(byte);
+            code += 1;
+        }
+        // A full block at the end needs no code byte after it
+        if byte == 0 || (code == 0xff && i + 1 < data.len()) {
+            encoded[code_at] = code;
+            code_at = encoded.len();
+            encoded.push(0);
+            code = 1;
+        }
+    }
+    encoded[code_at] = code;
+    encoded
+}
+
+/// Reverses [`cobs_encode`], `data` is a frame without its delimiter
+pub fn cobs_decode(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let code = data[i] as usize;
+        if code == 0 || i + code > data.len() {
+            return Err(DecodeError::Cobs);
+        }
+        decoded.extend_from_slice(&data[i + 1..i + code]);
+        i += code;
+        if code < 0xff && i < data.len() {
+            decoded.push(0);
+        }
+    }
+    Ok(decoded)
+}
+
+fn floats(sample: &Sample) -> impl Iterator<Item = f32> + '_ {
+    std::iter::once(sample.dt)
+        .chain(sample.accel)
+        .chain(sample.gyro)
+        .chain(sample.cal_mag)
+        .chain(sample.state[0])
+        .chain(sample.raw_mag)
+}
+
+/// Encodes `sample` as a complete frame, including the delimiter
+pub fn encode(sample: &Sample) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER + 4 * FLOATS + 4 + CRC);
+    message.push(SAMPLE);
+    message.push(if sample.seq.is_some() { HAS_SEQ } else { 0 });
+    for value in floats(sample) {
+        message.extend_from_slice(&value.to_le_bytes());
+    }
+    if let Some(seq) = sample.seq {
+        message.extend_from_slice(&seq.to_le_bytes());
+    }
+    let crc = crc16(&message);
+    message.extend_from_slice(&crc.to_le_bytes());
+    let mut frame = cobs_encode(&message);
+    frame.push(DELIMITER);
+    frame
+}
+
+/// Decodes a frame written by [`encode`], without its delimiter
+pub fn decode(frame: &[u8]) -> Result<Sample, DecodeError> {
+    let message = cobs_decode(frame)?;
+    if message.len() < HEADER + CRC {
+        return Err(DecodeError::Length {
+            expected: HEADER + 4 * FLOATS + CRC,
+            got: message.len(),
+        });
+    }
+    let (body, crc) = message.split_at(message.len() - CRC);
+    let got = u16::from_le_bytes([crc[0], crc[1]]);
+    let expected = crc16(body);
+    if got != expected {
+        return Err(DecodeError::Crc { expected, got });
+    }
+    if body[0] != SAMPLE {
+        return Err(DecodeError::UnknownMessage(body[0]));
+    }
+    let has_seq = body[1] & HAS_SEQ != 0;
+    let length = HEADER + 4 * FLOATS + if has_seq { 4 } else { 0 };
+    if body.len() != length {
+        return Err(DecodeError::Length {
+            expected: length + CRC,
+            got: message.len(),
+        });
+    }
+    let mut words = body[HEADER..]
+        .chunks_exact(4)
+        .map(|w| [w[0], w[1], w[2], w[3]]);
+    let mut values = words.by_ref().take(FLOATS).map(f32::from_le_bytes);
+    let mut next = || values.next().expect("length was checked");
+    let mut sample = Sample {
+        dt: next(),
+        ..Default::default()
+    };
+    for field in [&mut sample.accel, &mut sample.gyro, &mut sample.cal_mag] {
+        *field = [next(), next(), next()];
+    }
+    sample.state[0] = [(); 7].map(|_| next());
+    sample.raw_mag = [next(), next(), next()];
+    sample.seq = words.next().map(u32::from_le_bytes);
+    if !floats(&sample).all(f32::is_finite) {
+        return Err(DecodeError::NotFinite);
+    }
+    Ok(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(seq: Option<u32>) -> Sample {
+        let mut values = (1..).map(|i| i as f32 * 0.25 - 3.0);
+        let mut next = || values.next().unwrap();
+        Sample {
+            dt: 0.01,
+            accel: [next(), next(), next()],
+            gyro: [next(), next(), next()],
+            cal_mag: [next(), next(), next()],
+            state: [[(); 7].map(|_| next())],
+            raw_mag: [next(), next(), next()],
+            seq,
+        }
+    }
+
+    /// Frames `message` with a valid CRC, without the delimiter
+    fn frame(mut message: Vec<u8>) -> Vec<u8> {
+        let crc = crc16(&message);
+        message.extend_from_slice(&crc.to_le_bytes());
+        cobs_encode(&message)
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+        assert_eq!(crc16(b""), 0xffff);
+    }
+
+    #[test]
+    fn cobs_reference_vectors() {
+        let block: Vec<u8> = (0x01..=0xfe).collect();
+        let cases: [(Vec<u8>, Vec<u8>); 8] = [
+            (vec![], vec![0x01]),
+            (vec![0x00], vec![0x01, 0x01]),
+            (vec![0x00, 0x00], vec![0x01, 0x01, 0x01]),
+            (
+                vec![0x11, 0x22, 0x00, 0x33],
+                vec![0x03, 0x11, 0x22, 0x02, 0x33],
+            ),
+            // 254 bytes fill a block, no code byte follows it
+            (block.clone(), [vec![0xff], block.clone()].concat()),
+            (
+                [vec![0x00], block.clone()].concat(),
+                [vec![0x01, 0xff], block.clone()].concat(),
+            ),
+            // 255 bytes start a second block
+            (
+                [block.clone(), vec![0xff]].concat(),
+                [vec![0xff], block.clone(), vec![0x02, 0xff]].concat(),
+            ),
+            (
+                [block.clone(), vec![0x00]].concat(),
+                [vec![0xff], block.clone(), vec![0x01, 0x01]].concat(),
+            ),
+        ];
+        for (data, encoded) in cases {
+            assert_eq!(cobs_encode(&data), encoded, "{:02x?}", data);
+            assert_eq!(cobs_decode(&encoded), Ok(data));
+        }
+    }
+
+    #[test]
+    fn cobs_round_trips_around_block_boundaries() {
+        for len in [0, 1, 253, 254, 255, 256, 508, 509, 510] {
+            for zero_every in [None, Some(7), Some(254)] {
+                let data: Vec<u8> = (0..len)
+                    .map(|i| match zero_every {
+                        Some(n) if i % n == n - 1 => 0,
+                        _ => (i % 255 + 1) as u8,
+                    })
+                    .collect();
+                let encoded = cobs_encode(&data);
+                assert!(!encoded.contains(&0), "{} {:?}", len, zero_every);
+                assert_eq!(cobs_decode(&encoded), Ok(data), "{} {:?}", len, zero_every);
+            }
+        }
+        assert_eq!(cobs_decode(&[0x03, 0x11]), Err(DecodeError::Cobs));
+        assert_eq!(cobs_decode(&[0x02, 0x11, 0x00]), Err(DecodeError::Cobs));
+    }
+
+    #[test]
+    fn round_trips_with_and_without_seq() {
+        for seq in [None, Some(0), Some(0xdead_beef)] {
+            let sample = sample(seq);
+            let encoded = encode(&sample);
+            // Header table: 86 to 90 bytes including the delimiter
+            assert_eq!(encoded.len(), if seq.is_some() { 90 } else { 86 });
+            let (delimiter, frame) = encoded.split_last().unwrap();
+            assert_eq!(*delimiter, DELIMITER);
+            assert!(!frame.contains(&DELIMITER));
+            assert_eq!(decode(frame), Ok(sample));
+        }
+    }
+
+    #[test]
+    fn rejects_corrupted_frames() {
+        let mut message = cobs_decode(encode(&sample(Some(7))).split_last().unwrap().1).unwrap();
+        message[10] ^= 0x04;
+        let corrupted = cobs_encode(&message);
+        assert!(matches!(decode(&corrupted), Err(DecodeError::Crc { .. })));
+    }
+
+    #[test]
+    fn rejects_wrong_lengths() {
+        let message = cobs_decode(encode(&sample(None)).split_last().unwrap().1).unwrap();
+        let body = &message[..message.len() - CRC];
+        // Flag promises a seq that is not there
+        let mut flagged = body.to_vec();
+        flagged[1] |= HAS_SEQ;
+        assert_eq!(
+            decode(&frame(flagged)),
+            Err(DecodeError::Length {
+                expected: 88,
+                got: 84
+            })
+        );
+        assert_eq!(
+            decode(&frame(body[..40].to_vec())),
+            Err(DecodeError::Length {
+                expected: 84,
+                got: 42
+            })
+        );
+        assert!(matches!(
+            decode(&cobs_encode(&[SAMPLE, 0])),
+            Err(DecodeError::Length { got: 2, .. })
+        ));
+        let mut other = body.to_vec();
+        other[0] = 0x02;
+        assert_eq!(decode(&frame(other)), Err(DecodeError::UnknownMessage(2)));
+    }
+
+    #[test]
+    fn rejects_non_finite_values() {
+        for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let mut sample = sample(None);
+            sample.raw_mag[1] = value;
+            let encoded = encode(&sample);
+            assert_eq!(
+                decode(encoded.split_last().unwrap().1),
+                Err(DecodeError::NotFinite)
+            );
+        }
+    }
+}
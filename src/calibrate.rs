@@ -0,0 +1,132 @@
+//! Headless calibration of a recorded session and export of saved calibrations
+use std::error::Error;
+use std::path::PathBuf;
+
+use clap::Args;
+
+use bevy_mag::document::{self, CalibrationDocument};
+use bevy_mag::export::{self, ExportFormat};
+use bevy_mag::refine::RefineOptions;
+use bevy_mag::{geomag, math, Calibration, FitOptions, Sample, NT_PER_UNIT};
+
+#[derive(Args, Debug)]
+pub struct CalibrateArgs {
+    /// Recorded session, one JSON sample per line
+    #[arg(long)]
+    input: PathBuf,
+    /// Expected field strength in sensor units
+    #[arg(long, required_unless_present_all = ["lat", "lon"])]
+    field: Option<f64>,
+    /// Latitude in degrees to derive the field strength from the field model
+    #[arg(long, allow_hyphen_values = true, requires = "lon")]
+    lat: Option<f64>,
+    /// Longitude in degrees to derive the field strength from the field model
+    #[arg(long, allow_hyphen_values = true, requires = "lat")]
+    lon: Option<f64>,
+    /// Altitude above WGS-84 ellipsoid in km
+    #[arg(long, default_value_t = 0.0)]
+    alt: f64,
+    /// Decimal year, defaults to now
+    #[arg(long)]
+    year: Option<f64>,
+    /// One of offset, diagonal, full; picked from the spread of samples if omitted
+    #[arg(long)]
+    model: Option<math::FitModel>,
+    /// Reject samples far off the fitted ellipsoid
+    #[arg(long)]
+    robust: bool,
+    /// Minimise the geometric error with Levenberg-Marquardt after the fit
+    #[arg(long)]
+    refine: bool,
+    /// Where to write the calibration, `.toml` or `.json`; JSON to stdout if omitted
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    /// Saved calibration, `.json` or `.toml`
+    #[arg(long)]
+    calibration: PathBuf,
+    /// One of c, rust, floats, px4, ardupilot
+    #[arg(long)]
+    format: ExportFormat,
+    /// Where to write the result, stdout if omitted
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+/// Reads `raw_mag` of every parsable line, returns samples and the number of rejected lines
+fn read_raw_mag(text: &str) -> (Vec<[f64; 3]>, usize) {
+    let mut samples = vec![];
+    let mut rejected = 0;
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+        match serde_json::from_str::<Sample>(line) {
+            Ok(s) => samples.push(s.raw_mag.map(|c| c as f64)),
+            Err(_) => rejected += 1,
+        }
+    }
+    (samples, rejected)
+}
+
+pub fn run(args: CalibrateArgs) -> Result<(), Box<dyn Error>> {
+    let field = match (args.field, args.lat, args.lon) {
+        (Some(f), _, _) => f,
+        (None, Some(lat), Some(lon)) => {
+            let model = geomag::wmm();
+            let year = args.year.unwrap_or_else(geomag::current_year);
+            if !model.is_valid(year) {
+                eprintln!("warning: {} is not valid for {:.1}", model.name, year);
+            }
+            model.field(lat, lon, args.alt, year).f / NT_PER_UNIT
+        }
+        _ => unreachable!("clap requires --field or --lat and --lon"),
+    };
+
+    let text = std::fs::read_to_string(&args.input)
+        .map_err(|e| format!("{}: {}", args.input.display(), e))?;
+    let (samples, rejected) = read_raw_mag(&text);
+    eprintln!(
+        "{}: {} samples, {} lines rejected",
+        args.input.display(),
+        samples.len(),
+        rejected
+    );
+
+    let options = FitOptions {
+        model: args.model,
+        robust: args.robust.then(math::RobustOptions::default),
+        refine: args.refine.then(RefineOptions::default),
+    };
+    let (calibration, rejected) = Calibration::fit_with(&samples, field, &options)?;
+    if args.robust {
+        eprintln!("{} samples rejected as outliers", rejected.len());
+    }
+    eprintln!("field: {:.3}", field);
+    eprintln!("a_1: {}", calibration.a_1);
+    eprintln!("b: {}", calibration.b.transpose());
+    if let Some(report) = calibration.report {
+        eprintln!("{}", report);
+    }
+
+    match args.output {
+        Some(path) => {
+            document::save(&calibration, &path).map_err(|e| format!("{}: {}", path.display(), e))?
+        }
+        None => println!("{}", CalibrationDocument::from(&calibration).to_json()?),
+    }
+    Ok(())
+}
+
+pub fn run_export(args: ExportArgs) -> Result<(), Box<dyn Error>> {
+    let calibration = document::load(&args.calibration)
+        .map_err(|e| format!("{}: {}", args.calibration.display(), e))?;
+    let text = export::render(&calibration, args.format)?;
+    match args.output {
+        Some(path) => {
+            std::fs::write(&path, text).map_err(|e| format!("{}: {}", path.display(), e))?
+        }
+        None => print!("{}", text),
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+//! Commands sent to the device over the sample port
+//!
+//! A command is one NMEA style line, `$NAME,field,...*CS\r\n`, where `CS` is the XOR
+//! of all bytes between `$` and `*` as two hex digits. The device answers with
+//! `$ACK,NAME` once the command is applied or `$NAK,NAME,reason` if it is rejected.
+//! Replies may leave out the checksum, if it is there it must match.
+use std::fmt::Write;
+
+use crate::Calibration;
+
+/// Name of the command carrying `a_1` and `b`
+pub const CALIBRATION: &str = "CAL";
+
+/// XOR of all bytes of `body`
+pub fn checksum(body: &str) -> u8 {
+    body.bytes().fold(0, |cs, b| cs ^ b)
+}
+
+/// Frames `name` and `fields` into a command line
+pub fn frame(name: &str, fields: &[String]) -> String {
+    let mut body = name.to_string();
+    for field in fields {
+        write!(body, ",{}", field).expect("writing to String");
+    }
+    format!("${}*{:02X}\r\n", body, checksum(&body))
+}
+
+/// `$CAL` with `a_1` row by row followed by `b`
+pub fn calibration_command(calibration: &Calibration) -> String {
+    let fields: Vec<String> = calibration
+        .a_1
+        .row_iter()
+        .flat_map(|r| r.iter().copied().collect::<Vec<_>>())
+        .chain(calibration.b.iter().copied())
+        .map(|v| format!("{:.9e}", v as f32))
+        .collect();
+    frame(CALIBRATION, &fields)
+}
+
+/// Answer of the device to a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Ack,
+    Nak(String),
+}
+
+/// Name and fields of a command line, `None` if it is not one or its checksum is wrong
+///
+/// The checksum is optional, a line without `*CS` is accepted as it is.
+pub fn unframe(line: &str) -> Option<(&str, Vec<&str>)> {
+    let line = line.trim().strip_prefix('$')?;
+    let body = match line.split_once('*') {
+        Some((body, cs)) if cs.len() == 2 => {
+            let cs = u8::from_str_radix(cs, 16).ok()?;
+            (cs == checksum(body)).then_some(body)?
+        }
+        Some(_) => return None,
+        None => line,
+    };
+    let mut fields = body.split(',');
+    let name = fields.next()?;
+    Some((name, fields.collect()))
+}
+
+/// Returns the reply to command `name` if `line` is one
+pub fn parse_reply(line: &str, name: &str) -> Option<Reply> {
+    let (kind, fields) = unframe(line)?;
+    if fields.first() != Some(&name) {
+        return None;
+    }
+    match kind {
+        "ACK" => Some(Reply::Ack),
+        "NAK" => Some(Reply::Nak(fields[1..].join(","))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Matrix3, Vector3};
+
+    #[test]
+    fn frame_appends_checksum() {
+        // 'A' ^ 'C' ^ 'K' ^ ',' ^ 'C' ^ 'A' ^ 'L'
+        assert_eq!(frame("ACK", &["CAL".to_string()]), "$ACK,CAL*2B\r\n");
+        assert_eq!(checksum("ACK,CAL"), 0x2b);
+    }
+
+    #[test]
+    fn calibration_command_round_trips() {
+        let calibration = Calibration {
+            a_1: Matrix3::new(1.1, 0.02, 0.04, 0.02, 0.9, 0.06, 0.04, 0.06, 1.0),
+            b: Vector3::new(120.5, -80.25, 40.0),
+            ..Default::default()
+        };
+        let line = calibration_command(&calibration);
+        assert!(line.ends_with("\r\n"));
+        let (name, fields) = unframe(&line).unwrap();
+        assert_eq!(name, CALIBRATION);
+        let values: Vec<f32> = fields.iter().map(|f| f.parse().unwrap()).collect();
+        let expected: Vec<f32> = calibration
+            .a_1
+            .transpose()
+            .iter()
+            .chain(calibration.b.iter())
+            .map(|v| *v as f32)
+            .collect();
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn parses_ack_and_nak() {
+        assert_eq!(parse_reply("$ACK,CAL*2B\r\n", "CAL"), Some(Reply::Ack));
+        assert_eq!(parse_reply("$ACK,CAL", "CAL"), Some(Reply::Ack));
+        let nak = frame("NAK", &["CAL".to_string(), "singular, a_1".to_string()]);
+        assert_eq!(
+            parse_reply(&nak, "CAL"),
+            Some(Reply::Nak("singular, a_1".to_string()))
+        );
+        assert_eq!(parse_reply("$ACK,RATE", "CAL"), None);
+        assert_eq!(parse_reply("{\"dt\": 0.01}", "CAL"), None);
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        assert_eq!(parse_reply("$ACK,CAL*2C", "CAL"), None);
+        assert_eq!(parse_reply("$ACK,CAL*6", "CAL"), None);
+        assert_eq!(parse_reply("$ACK,CAL*zz", "CAL"), None);
+        assert_eq!(unframe("$CAL,1,2*00"), None);
+    }
+}
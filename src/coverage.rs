@@ -0,0 +1,275 @@
+//! How much of the sphere of field directions the samples cover
+//!
+//! The unit sphere is split into the faces of a subdivided icosahedron, a sample
+//! marks the face whose center is closest to its direction from the center of the
+//! readings. Raw readings are offset by the hard iron, so the center is the offset of
+//! the latest fit or, before there is one, the mean of the readings. Readings are
+//! binned again whenever the center moves.
+use std::collections::HashMap;
+
+use nalgebra::Vector3;
+
+/// Subdivisions of the default tessellation, 80 faces
+pub const SUBDIVISIONS: usize = 1;
+
+/// Movement of the center, relative to the radius of the readings, that bins them again
+const REBIN_AFTER: f64 = 0.02;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "bevy", derive(bevy::prelude::Resource))]
+pub struct Coverage {
+    vertices: Vec<Vector3<f64>>,
+    faces: Vec<[usize; 3]>,
+    centers: Vec<Vector3<f64>>,
+    counts: Vec<usize>,
+    /// Readings added, to bin them again when the center moves
+    readings: Vec<Vector3<f64>>,
+    sum: Vector3<f64>,
+    sum_squares: f64,
+    /// Hard iron offset of a fit, the mean of the readings is used without one
+    offset: Option<Vector3<f64>>,
+    /// Center the counts were binned around
+    binned_around: Vector3<f64>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new(SUBDIVISIONS)
+    }
+}
+
+/// Vertices on the unit sphere and faces of an icosahedron subdivided `subdivisions` times
+pub fn icosphere(subdivisions: usize) -> (Vec<Vector3<f64>>, Vec<[usize; 3]>) {
+    let t = (1.0 + 5f64.sqrt()) / 2.0;
+    let mut vertices: Vec<Vector3<f64>> = [
+        [-1., t, 0.],
+        [1., t, 0.],
+        [-1., -t, 0.],
+        [1., -t, 0.],
+        [0., -1., t],
+        [0., 1., t],
+        [0., -1., -t],
+        [0., 1., -t],
+        [t, 0., -1.],
+        [t, 0., 1.],
+        [-t, 0., -1.],
+        [-t, 0., 1.],
+    ]
+    .iter()
+    .map(|v| Vector3::from(*v).normalize())
+    .collect();
+    let mut faces = vec![
+        [0, 11, 5],
+        [0, 5, 1],
+        [0, 1, 7],
+        [0, 7, 10],
+        [0, 10, 11],
+        [1, 5, 9],
+        [5, 11, 4],
+        [11, 10, 2],
+        [10, 7, 6],
+        [7, 1, 8],
+        [3, 9, 4],
+        [3, 4, 2],
+        [3, 2, 6],
+        [3, 6, 8],
+        [3, 8, 9],
+        [4, 9, 5],
+        [2, 4, 11],
+        [6, 2, 10],
+        [8, 6, 7],
+        [9, 8, 1],
+    ];
+    for _ in 0..subdivisions {
+        // Edges are shared by two faces, reuse their midpoints
+        let mut midpoints = HashMap::new();
+        let mut midpoint = |a: usize, b: usize, vertices: &mut Vec<Vector3<f64>>| {
+            *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                vertices.push(((vertices[a] + vertices[b]) / 2.0).normalize());
+                vertices.len() - 1
+            })
+        };
+        faces = faces
+            .iter()
+            .flat_map(|&[a, b, c]| {
+                let ab = midpoint(a, b, &mut vertices);
+                let bc = midpoint(b, c, &mut vertices);
+                let ca = midpoint(c, a, &mut vertices);
+                [[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+            })
+            .collect();
+    }
+    (vertices, faces)
+}
+
+impl Coverage {
+    pub fn new(subdivisions: usize) -> Self {
+        let (vertices, faces) = icosphere(subdivisions);
+        let centers = faces
+            .iter()
+            .map(|f| (vertices[f[0]] + vertices[f[1]] + vertices[f[2]]).normalize())
+            .collect();
+        let counts = vec![0; faces.len()];
+        Coverage {
+            vertices,
+            faces,
+            centers,
+            counts,
+            readings: vec![],
+            sum: Vector3::zeros(),
+            sum_squares: 0.0,
+            offset: None,
+            binned_around: Vector3::zeros(),
+        }
+    }
+
+    /// Face the direction of `v` falls into, `None` for the zero vector
+    pub fn bin(&self, v: &[f32; 3]) -> Option<usize> {
+        self.bin_direction(&Vector3::from(v.map(|c| c as f64)))
+    }
+
+    fn bin_direction(&self, v: &Vector3<f64>) -> Option<usize> {
+        if v.norm() == 0.0 || !v.norm().is_finite() {
+            return None;
+        }
+        (0..self.centers.len())
+            .max_by(|&i, &j| self.centers[i].dot(v).total_cmp(&self.centers[j].dot(v)))
+    }
+
+    /// Adds a raw reading and marks the face of its direction from the center, returns it
+    pub fn add(&mut self, raw: &[f32; 3]) -> Option<usize> {
+        let raw = Vector3::from(raw.map(|c| c as f64));
+        if !raw.iter().all(|c| c.is_finite()) {
+            return None;
+        }
+        self.readings.push(raw);
+        self.sum += raw;
+        self.sum_squares += raw.norm_squared();
+        if self.rebin() {
+            return self.bin_direction(&(raw - self.binned_around));
+        }
+        let bin = self.bin_direction(&(raw - self.binned_around))?;
+        self.counts[bin] += 1;
+        Some(bin)
+    }
+
+    /// Centers the readings on the hard iron offset `b` of a fit, or on their mean if `None`
+    pub fn set_offset(&mut self, b: Option<Vector3<f64>>) {
+        self.offset = b;
+        self.rebin();
+    }
+
+    /// Offset of the fit or mean of the readings
+    pub fn center(&self) -> Vector3<f64> {
+        match self.offset {
+            Some(b) => b,
+            None if self.readings.is_empty() => Vector3::zeros(),
+            None => self.sum / self.readings.len() as f64,
+        }
+    }
+
+    /// RMS distance of the readings from their mean
+    fn radius(&self) -> f64 {
+        let n = self.readings.len().max(1) as f64;
+        let mean = self.sum / n;
+        (self.sum_squares / n - mean.norm_squared()).max(0.0).sqrt()
+    }
+
+    /// Bins all readings again if the center moved, returns whether it did
+    fn rebin(&mut self) -> bool {
+        let center = self.center();
+        if (center - self.binned_around).norm() <= REBIN_AFTER * self.radius() {
+            return false;
+        }
+        self.binned_around = center;
+        self.counts.iter_mut().for_each(|c| *c = 0);
+        for j in 0..self.readings.len() {
+            if let Some(bin) = self.bin_direction(&(self.readings[j] - center)) {
+                self.counts[bin] += 1;
+            }
+        }
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.iter_mut().for_each(|c| *c = 0);
+        self.readings.clear();
+        self.sum = Vector3::zeros();
+        self.sum_squares = 0.0;
+        self.offset = None;
+        self.binned_around = Vector3::zeros();
+    }
+
+    /// Number of faces with at least one sample
+    pub fn covered(&self) -> usize {
+        self.counts.iter().filter(|&&c| c > 0).count()
+    }
+
+    /// Covered part of the sphere, 0 to 1
+    pub fn fraction(&self) -> f64 {
+        self.covered() as f64 / self.counts.len() as f64
+    }
+
+    pub fn vertices(&self) -> &[Vector3<f64>] {
+        &self.vertices
+    }
+
+    pub fn faces(&self) -> &[[usize; 3]] {
+        &self.faces
+    }
+
+    /// Samples per face
+    pub fn counts(&self) -> &[usize] {
+        &self.counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Readings over the whole sphere of radius 500 around `b`
+    fn offset_sphere(b: Vector3<f64>) -> Vec<[f32; 3]> {
+        let (vertices, _) = icosphere(3);
+        vertices
+            .iter()
+            .map(|v| (v * 500.0 + b).map(|c| c as f32).into())
+            .collect()
+    }
+
+    #[test]
+    fn offset_sphere_is_covered_around_its_mean() {
+        let mut coverage = Coverage::default();
+        for raw in offset_sphere(Vector3::new(900.0, -700.0, 400.0)) {
+            coverage.add(&raw);
+        }
+        assert_eq!(coverage.covered(), coverage.counts().len());
+    }
+
+    #[test]
+    fn offset_of_fit_rebins_readings() {
+        let b = Vector3::new(900.0, -700.0, 400.0);
+        let mut coverage = Coverage::default();
+        // Only the upper half, whose mean is well above b
+        for raw in offset_sphere(b).into_iter().filter(|r| r[2] as f64 > b.z) {
+            coverage.add(&raw);
+        }
+        let around_mean = coverage.covered();
+        coverage.set_offset(Some(b));
+        assert_eq!(coverage.center(), b);
+        assert_eq!(coverage.counts().iter().sum::<usize>(), coverage.readings.len());
+        let around_b = coverage.covered();
+        assert!(around_b < around_mean, "{} {}", around_b, around_mean);
+        assert!((around_b as f64 - 40.0).abs() <= 8.0, "{}", around_b);
+    }
+
+    #[test]
+    fn clear_forgets_readings_and_offset() {
+        let mut coverage = Coverage::default();
+        coverage.set_offset(Some(Vector3::new(1.0, 2.0, 3.0)));
+        coverage.add(&[1.0, 2.0, 4.0]);
+        coverage.clear();
+        assert_eq!(coverage.covered(), 0);
+        assert_eq!(coverage.center(), Vector3::zeros());
+    }
+}
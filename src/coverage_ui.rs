@@ -0,0 +1,145 @@
+//! Shows which field directions are still missing and when collection is complete
+use bevy::prelude::*;
+use bevy::render::mesh::PrimitiveTopology;
+use bevy_egui::{egui, EguiContexts};
+
+use bevy_mag::coverage::Coverage;
+
+use crate::Field;
+
+/// When "Done" is triggered without pressing it
+#[derive(Resource)]
+pub struct AutoDone {
+    pub enabled: bool,
+    /// Covered part of the sphere, 0 to 1
+    pub coverage: f64,
+    pub samples: usize,
+    /// Sample count of the last automatic attempt
+    attempted_at: usize,
+}
+
+/// Samples to wait for before retrying a failed automatic fit
+const RETRY_AFTER: usize = 100;
+
+impl Default for AutoDone {
+    fn default() -> Self {
+        AutoDone {
+            enabled: false,
+            coverage: 0.9,
+            samples: 500,
+            attempted_at: 0,
+        }
+    }
+}
+
+impl AutoDone {
+    /// Whether to fit now, each sample count is attempted once
+    pub fn ready(&mut self, coverage: &Coverage, samples: usize) -> bool {
+        let ready = self.enabled
+            && coverage.fraction() >= self.coverage
+            && samples >= self.samples
+            && samples >= self.attempted_at + RETRY_AFTER;
+        if ready {
+            self.attempted_at = samples;
+        }
+        ready
+    }
+}
+
+/// Uncovered faces, drawn over the target sphere
+#[derive(Component)]
+struct CoverageOverlay;
+
+pub struct CoveragePlugin;
+
+impl Plugin for CoveragePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Coverage>()
+            .init_resource::<AutoDone>()
+            .add_startup_system(spawn_overlay)
+            .add_system(update_overlay)
+            .add_system(draw_coverage_ui);
+    }
+}
+
+/// Triangles of the faces without samples
+fn uncovered_mesh(coverage: &Coverage) -> Mesh {
+    let vertices = coverage.vertices();
+    let positions: Vec<[f32; 3]> = coverage
+        .faces()
+        .iter()
+        .zip(coverage.counts())
+        .filter(|(_, &count)| count == 0)
+        .flat_map(|(face, _)| face.map(|i| vertices[i].map(|c| c as f32).into()))
+        .collect();
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, positions.clone());
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh
+}
+
+fn spawn_overlay(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    coverage: Res<Coverage>,
+    field: Res<Field>,
+) {
+    commands.spawn((
+        PbrBundle {
+            mesh: meshes.add(uncovered_mesh(&coverage)),
+            material: materials.add(StandardMaterial {
+                base_color: Color::rgba(0.2, 0.5, 1.0, 0.15),
+                alpha_mode: AlphaMode::Blend,
+                unlit: true,
+                double_sided: true,
+                cull_mode: None,
+                ..default()
+            }),
+            transform: Transform::from_scale(Vec3::splat(field.f)),
+            ..default()
+        },
+        CoverageOverlay,
+    ));
+}
+
+fn update_overlay(
+    coverage: Res<Coverage>,
+    field: Res<Field>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut overlays: Query<(&Handle<Mesh>, &mut Transform), With<CoverageOverlay>>,
+) {
+    for (handle, mut transform) in &mut overlays {
+        if coverage.is_changed() {
+            if let Some(mesh) = meshes.get_mut(handle) {
+                *mesh = uncovered_mesh(&coverage);
+            }
+        }
+        if field.is_changed() {
+            transform.scale = Vec3::splat(field.f);
+        }
+    }
+}
+
+fn draw_coverage_ui(
+    mut contexts: EguiContexts,
+    coverage: Res<Coverage>,
+    mut auto_done: ResMut<AutoDone>,
+) {
+    egui::Window::new("Coverage").show(contexts.ctx_mut(), |ui| {
+        let fraction = coverage.fraction();
+        ui.add(egui::ProgressBar::new(fraction as f32).text(format!(
+            "{:.0}%, {} of {} bins",
+            fraction * 100.0,
+            coverage.covered(),
+            coverage.counts().len()
+        )));
+        ui.checkbox(&mut auto_done.enabled, "Done automatically");
+        ui.add_enabled_ui(auto_done.enabled, |ui| {
+            let mut percent = auto_done.coverage * 100.0;
+            ui.add(egui::Slider::new(&mut percent, 10.0..=100.0).text("% coverage"));
+            auto_done.coverage = percent / 100.0;
+            ui.add(egui::Slider::new(&mut auto_done.samples, 10..=5000).text("samples"));
+        });
+    });
+}
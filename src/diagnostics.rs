@@ -0,0 +1,216 @@
+//! Lines that could not be parsed and samples the device sent but that never arrived
+//!
+//! Gaps are only detected if the firmware numbers its samples with `seq`.
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use bevy_mag::Sample;
+
+/// Rejected lines kept for display
+const REJECTED_KEPT: usize = 50;
+
+/// Characters of a rejected line shown
+const SHOWN_CHARS: usize = 200;
+
+pub struct Rejected {
+    /// Number of the line among all lines read, from 1
+    pub line_number: u64,
+    pub line: String,
+    pub error: String,
+}
+
+#[derive(Resource, Default)]
+pub struct Diagnostics {
+    pub lines: u64,
+    pub parse_errors: u64,
+    rejected: VecDeque<Rejected>,
+    /// Last sequence number seen
+    seq: Option<u32>,
+    pub numbered: u64,
+    pub gaps: u64,
+    /// Samples missing in gaps
+    pub lost: u64,
+    /// Times the sequence went back, e.g. when the device restarted
+    pub restarts: u64,
+    /// Last gap, as the first and last missing sequence number
+    pub last_gap: Option<(u32, u32)>,
+}
+
+impl Diagnostics {
+    pub fn accepted(&mut self, sample: &Sample) {
+        self.lines += 1;
+        let Some(seq) = sample.seq else {
+            return;
+        };
+        self.numbered += 1;
+        if let Some(last) = self.seq {
+            let expected = last.wrapping_add(1);
+            match seq.wrapping_sub(expected) {
+                0 => {}
+                missing if missing < u32::MAX / 2 => {
+                    self.gaps += 1;
+                    self.lost += missing as u64;
+                    self.last_gap = Some((expected, seq.wrapping_sub(1)));
+                }
+                _ => self.restarts += 1,
+            }
+        }
+        self.seq = Some(seq);
+    }
+
+    pub fn rejected(&mut self, line: &str, error: impl ToString) {
+        self.lines += 1;
+        self.parse_errors += 1;
+        if self.rejected.len() == REJECTED_KEPT {
+            self.rejected.pop_front();
+        }
+        self.rejected.push_back(Rejected {
+            line_number: self.lines,
+            line: line.to_string(),
+            error: error.to_string(),
+        });
+    }
+
+    /// Samples lost relative to the ones sent, 0 to 1
+    pub fn loss(&self) -> f64 {
+        let sent = self.numbered + self.lost;
+        if sent == 0 {
+            return 0.0;
+        }
+        self.lost as f64 / sent as f64
+    }
+}
+
+pub struct DiagnosticsPlugin;
+
+impl Plugin for DiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Diagnostics>()
+            .add_system(draw_diagnostics_ui);
+    }
+}
+
+fn draw_diagnostics_ui(mut contexts: EguiContexts, mut diagnostics: ResMut<Diagnostics>) {
+    egui::Window::new("Diagnostics")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::Grid::new("diagnostics").show(ui, |ui| {
+                for (label, count) in [
+                    ("Lines", diagnostics.lines),
+                    ("Parse errors", diagnostics.parse_errors),
+                    ("Numbered samples", diagnostics.numbered),
+                    ("Gaps", diagnostics.gaps),
+                    ("Lost samples", diagnostics.lost),
+                    ("Restarts", diagnostics.restarts),
+                ] {
+                    ui.label(label);
+                    ui.label(count.to_string());
+                    ui.end_row();
+                }
+            });
+            if diagnostics.numbered == 0 {
+                ui.label("Samples carry no `seq`, gaps cannot be detected");
+            } else {
+                ui.label(format!("{:.2}% lost", 100.0 * diagnostics.loss()));
+            }
+            if let Some((first, last)) = diagnostics.last_gap {
+                ui.label(format!("Last gap: {} to {}", first, last));
+            }
+            if ui.button("Clear").clicked() {
+                *diagnostics = Diagnostics::default();
+            }
+            ui.separator();
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .show(ui, |ui| {
+                    for rejected in diagnostics.rejected.iter().rev() {
+                        ui.label(format!("#{}: {}", rejected.line_number, rejected.error));
+                        let shown: String = rejected.line.chars().take(SHOWN_CHARS).collect();
+                        ui.monospace(shown);
+                    }
+                });
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(diagnostics: &mut Diagnostics, seqs: &[u32]) {
+        for seq in seqs {
+            let sample = Sample {
+                seq: Some(*seq),
+                ..Default::default()
+            };
+            diagnostics.accepted(&sample);
+        }
+    }
+
+    #[test]
+    fn counts_gaps_and_lost_samples() {
+        let mut diagnostics = Diagnostics::default();
+        numbered(&mut diagnostics, &[1, 2, 3, 6, 7, 10]);
+        assert_eq!(diagnostics.numbered, 6);
+        assert_eq!(diagnostics.gaps, 2);
+        assert_eq!(diagnostics.lost, 4);
+        assert_eq!(diagnostics.last_gap, Some((8, 9)));
+        assert_eq!(diagnostics.restarts, 0);
+        assert!((diagnostics.loss() - 0.4).abs() < 1e-12);
+    }
+
+    #[test]
+    fn sequence_wraps_around() {
+        let mut diagnostics = Diagnostics::default();
+        numbered(&mut diagnostics, &[u32::MAX - 1, u32::MAX, 0, 1]);
+        assert_eq!((diagnostics.gaps, diagnostics.restarts), (0, 0));
+
+        let mut diagnostics = Diagnostics::default();
+        numbered(&mut diagnostics, &[u32::MAX - 1, 2]);
+        // u32::MAX, 0 and 1 are missing
+        assert_eq!(diagnostics.gaps, 1);
+        assert_eq!(diagnostics.lost, 3);
+        assert_eq!(diagnostics.last_gap, Some((u32::MAX, 1)));
+        assert_eq!(diagnostics.restarts, 0);
+    }
+
+    #[test]
+    fn sequence_going_back_is_a_restart() {
+        let mut diagnostics = Diagnostics::default();
+        numbered(&mut diagnostics, &[500, 501, 0, 1, 1]);
+        assert_eq!(diagnostics.restarts, 2);
+        assert_eq!(diagnostics.gaps, 0);
+        assert_eq!(diagnostics.lost, 0);
+        assert_eq!(diagnostics.loss(), 0.0);
+    }
+
+    #[test]
+    fn samples_without_seq_are_not_numbered() {
+        let mut diagnostics = Diagnostics::default();
+        numbered(&mut diagnostics, &[1]);
+        diagnostics.accepted(&Sample::default());
+        numbered(&mut diagnostics, &[2]);
+        assert_eq!(diagnostics.lines, 3);
+        assert_eq!(diagnostics.numbered, 2);
+        assert_eq!(diagnostics.gaps, 0);
+        assert_eq!(Diagnostics::default().loss(), 0.0);
+    }
+
+    #[test]
+    fn keeps_the_last_rejected_lines() {
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.accepted(&Sample::default());
+        for i in 0..REJECTED_KEPT + 10 {
+            diagnostics.rejected(&format!("line {}", i), "expected value");
+        }
+        assert_eq!(diagnostics.lines, REJECTED_KEPT as u64 + 11);
+        assert_eq!(diagnostics.parse_errors, REJECTED_KEPT as u64 + 10);
+        assert_eq!(diagnostics.rejected.len(), REJECTED_KEPT);
+        let first = diagnostics.rejected.front().unwrap();
+        assert_eq!((first.line_number, first.line.as_str()), (12, "line 10"));
+        let last = diagnostics.rejected.back().unwrap();
+        assert_eq!(last.line_number, REJECTED_KEPT as u64 + 11);
+        assert_eq!(last.error, "expected value");
+    }
+}
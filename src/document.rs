@@ -0,0 +1,250 @@
+//! Versioned calibration files
+//!
+//! The format is picked from the file extension: `.toml` for TOML, anything else is JSON.
+use std::fmt;
+use std::path::Path;
+
+use nalgebra::{Matrix3, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::imu::{AccelCalibration, GyroCalibration};
+use crate::math::FitReport;
+use crate::Calibration;
+
+/// Version written to new documents, documents of other versions are rejected on load
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationDocument {
+    pub version: u32,
+    /// Soft iron matrix, row by row
+    pub a_1: [[f64; 3]; 3],
+    /// Hard iron offset
+    pub b: [f64; 3],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fit: Option<FitReport>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accel: Option<AccelDocument>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gyro: Option<GyroDocument>,
+}
+
+/// Accelerometer calibration, calibrated is `a * (raw - bias)`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccelDocument {
+    /// Scale and misalignment, row by row
+    pub a: [[f64; 3]; 3],
+    pub bias: [f64; 3],
+}
+
+/// Gyroscope bias, calibrated is `raw - bias`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GyroDocument {
+    pub bias: [f64; 3],
+    /// Sensor units per `sqrt(Hz)`
+    pub noise_density: [f64; 3],
+}
+
+#[derive(Debug)]
+pub enum DocumentError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    TomlRead(toml::de::Error),
+    TomlWrite(toml::ser::Error),
+    /// Document was written by a different version of the tool
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for DocumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DocumentError::Io(e) => write!(f, "{}", e),
+            DocumentError::Json(e) => write!(f, "invalid JSON: {}", e),
+            DocumentError::TomlRead(e) => write!(f, "invalid TOML: {}", e),
+            DocumentError::TomlWrite(e) => write!(f, "cannot write TOML: {}", e),
+            DocumentError::UnsupportedVersion(v) => {
+                write!(f, "unsupported version {}, expected {}", v, VERSION)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DocumentError {}
+
+impl From<&Calibration> for CalibrationDocument {
+    fn from(calibration: &Calibration) -> Self {
+        CalibrationDocument {
+            version: VERSION,
+            a_1: calibration.a_1.transpose().into(),
+            b: calibration.b.into(),
+            fit: calibration.report,
+            accel: calibration.accel.map(|accel| AccelDocument {
+                a: accel.a.transpose().into(),
+                bias: accel.bias.into(),
+            }),
+            gyro: calibration.gyro.map(|gyro| GyroDocument {
+                bias: gyro.bias.into(),
+                noise_density: gyro.noise_density.into(),
+            }),
+        }
+    }
+}
+
+impl From<CalibrationDocument> for Calibration {
+    fn from(document: CalibrationDocument) -> Self {
+        Calibration {
+            a_1: Matrix3::from(document.a_1).transpose(),
+            b: Vector3::from(document.b),
+            report: document.fit,
+            accel: document.accel.map(|accel| AccelCalibration {
+                a: Matrix3::from(accel.a).transpose(),
+                bias: Vector3::from(accel.bias),
+            }),
+            gyro: document.gyro.map(|gyro| GyroCalibration {
+                bias: Vector3::from(gyro.bias),
+                noise_density: Vector3::from(gyro.noise_density),
+            }),
+        }
+    }
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("toml"))
+}
+
+impl CalibrationDocument {
+    pub fn to_json(&self) -> Result<String, DocumentError> {
+        serde_json::to_string_pretty(self).map_err(DocumentError::Json)
+    }
+
+    pub fn to_toml(&self) -> Result<String, DocumentError> {
+        toml::to_string(self).map_err(DocumentError::TomlWrite)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, DocumentError> {
+        serde_json::from_str::<Self>(text)
+            .map_err(DocumentError::Json)?
+            .checked()
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, DocumentError> {
+        toml::from_str::<Self>(text)
+            .map_err(DocumentError::TomlRead)?
+            .checked()
+    }
+
+    fn checked(self) -> Result<Self, DocumentError> {
+        if self.version != VERSION {
+            return Err(DocumentError::UnsupportedVersion(self.version));
+        }
+        Ok(self)
+    }
+}
+
+/// Writes `calibration` to `path`
+pub fn save(calibration: &Calibration, path: &Path) -> Result<(), DocumentError> {
+    let document = CalibrationDocument::from(calibration);
+    let text = if is_toml(path) {
+        document.to_toml()?
+    } else {
+        document.to_json()?
+    };
+    std::fs::write(path, text).map_err(DocumentError::Io)
+}
+
+/// Reads a calibration written by [`save`]
+pub fn load(path: &Path) -> Result<Calibration, DocumentError> {
+    let text = std::fs::read_to_string(path).map_err(DocumentError::Io)?;
+    let document = if is_toml(path) {
+        CalibrationDocument::from_toml(&text)?
+    } else {
+        CalibrationDocument::from_json(&text)?
+    };
+    Ok(document.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::FitModel;
+    use crate::refine::Refinement;
+
+    fn calibration() -> Calibration {
+        let a_1 = Matrix3::new(1.1, 0.02, 0.04, 0.03, 0.9, 0.06, 0.05, 0.07, 1.0);
+        let b = Vector3::new(120.5, -80.25, 40.125);
+        let samples: Vec<[f64; 3]> = [
+            [1.0, 0.0, 0.0],
+            [-1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, -1.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [0.0, 0.0, -1.0],
+        ]
+        .iter()
+        .map(|u| (Vector3::from(*u) * 480.0 + b).into())
+        .collect();
+        let mut report = FitReport::new(&samples, &a_1, &b, 486.0);
+        report.model = Some(FitModel::Full);
+        report.refinement = Some(Refinement {
+            iterations: 7,
+            initial_cost: 12.5,
+            cost: 3.0625,
+        });
+        report.undetermined = Some([0.0, 0.6, 0.8]);
+        Calibration {
+            a_1,
+            b,
+            report: Some(report),
+            accel: Some(AccelCalibration {
+                a: Matrix3::new(1.01, 0.002, -0.003, 0.001, 0.99, 0.004, -0.002, 0.005, 1.02),
+                bias: Vector3::new(0.05, -0.1, 0.2),
+            }),
+            gyro: Some(GyroCalibration {
+                bias: Vector3::new(0.01, -0.02, 0.03),
+                noise_density: Vector3::new(0.004, 0.005, 0.006),
+            }),
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let calibration = calibration();
+        let text = CalibrationDocument::from(&calibration).to_json().unwrap();
+        let loaded = Calibration::from(CalibrationDocument::from_json(&text).unwrap());
+        assert_eq!(loaded, calibration);
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let calibration = calibration();
+        let text = CalibrationDocument::from(&calibration).to_toml().unwrap();
+        let loaded = Calibration::from(CalibrationDocument::from_toml(&text).unwrap());
+        assert_eq!(loaded, calibration);
+    }
+
+    #[test]
+    fn matrices_are_written_row_by_row() {
+        let text = CalibrationDocument::from(&calibration()).to_json().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(value["a_1"][0], serde_json::json!([1.1, 0.02, 0.04]));
+        assert_eq!(value["a_1"][1], serde_json::json!([0.03, 0.9, 0.06]));
+        assert_eq!(
+            value["accel"]["a"][2],
+            serde_json::json!([-0.002, 0.005, 1.02])
+        );
+        assert_eq!(value["b"], serde_json::json!([120.5, -80.25, 40.125]));
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut document = CalibrationDocument::from(&calibration());
+        for version in [0, VERSION + 1] {
+            document.version = version;
+            let json = CalibrationDocument::from_json(&document.to_json().unwrap());
+            assert!(matches!(json, Err(DocumentError::UnsupportedVersion(v)) if v == version));
+            let toml = CalibrationDocument::from_toml(&document.to_toml().unwrap());
+            assert!(matches!(toml, Err(DocumentError::UnsupportedVersion(v)) if v == version));
+        }
+    }
+}
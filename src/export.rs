@@ -0,0 +1,378 @@
+//! Renders a calibration as source code to bake into firmware or as autopilot parameters
+//!
+//! PX4 and ArduPilot describe soft iron as a symmetric matrix stored as its diagonal
+//! (`XSCALE..`, `DIA_*`) and the three distinct off-diagonal elements `xy`, `xz`, `yz`
+//! (`XODIAG..`, `ODI_*`), see [`soft_iron_parts`].
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+use nalgebra::Matrix3;
+
+use crate::{Calibration, NT_PER_UNIT};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// `static const float MAG_A1[3][3]` and `MAG_B[3]`
+    CHeader,
+    /// `pub const MAG_A1: [[f32; 3]; 3]` and `MAG_B: [f32; 3]`
+    Rust,
+    /// Twelve comma separated floats, `a_1` row by row followed by `b`, after `#` comments
+    Floats,
+    /// `CAL_MAG0_*` parameters for QGroundControl
+    Px4,
+    /// `COMPASS_*` parameters for Mission Planner
+    ArduPilot,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 5] = [
+        ExportFormat::CHeader,
+        ExportFormat::Rust,
+        ExportFormat::Floats,
+        ExportFormat::Px4,
+        ExportFormat::ArduPilot,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportFormat::CHeader => "c",
+            ExportFormat::Rust => "rust",
+            ExportFormat::Floats => "floats",
+            ExportFormat::Px4 => "px4",
+            ExportFormat::ArduPilot => "ardupilot",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::CHeader => "h",
+            ExportFormat::Rust => "rs",
+            ExportFormat::Floats => "txt",
+            ExportFormat::Px4 | ExportFormat::ArduPilot => "params",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ExportFormat::ALL
+            .into_iter()
+            .find(|f| f.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = ExportFormat::ALL.iter().map(|f| f.name()).collect();
+                format!("unknown format {}, expected one of {}", s, names.join(", "))
+            })
+    }
+}
+
+/// A value of the calibration is NaN or infinite, which has no C or Rust literal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NotFinite;
+
+impl fmt::Display for NotFinite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "calibration holds a value that is NaN or infinite")
+    }
+}
+
+impl std::error::Error for NotFinite {}
+
+/// Formats with full f32 precision, valid both as C and Rust literal
+fn float(v: f64) -> String {
+    format!("{:.9e}", v as f32)
+}
+
+/// Comma separated literals, `suffix` is appended to each
+fn row(values: impl Iterator<Item = f64>, suffix: &str) -> String {
+    values
+        .map(|v| format!("{}{}", float(v), suffix))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Where the calibration came from and how good it is, one `prefix`ed comment line each
+fn comment(calibration: &Calibration, prefix: &str) -> String {
+    let mut lines =
+        vec!["Magnetometer calibration: calibrated = MAG_A1 * (raw - MAG_B)".to_string()];
+    if let Some(report) = &calibration.report {
+        lines.extend(report.to_string().lines().map(str::to_string));
+    }
+    lines.iter().map(|l| format!("{}{}\n", prefix, l)).collect()
+}
+
+/// Splits `a_1` into diagonal `[xx, yy, zz]` and off-diagonal `[xy, xz, yz]` elements
+///
+/// A fitted `a_1` is symmetric. If it is not, its symmetric part `(a_1 + a_1^T) / 2`
+/// is used, which drops any rotation the matrix contains.
+pub fn soft_iron_parts(a_1: &Matrix3<f64>) -> ([f64; 3], [f64; 3]) {
+    let s = (a_1 + a_1.transpose()) * 0.5;
+    (
+        [s[(0, 0)], s[(1, 1)], s[(2, 2)]],
+        [s[(0, 1)], s[(0, 2)], s[(1, 2)]],
+    )
+}
+
+/// PX4 computes `S * (raw - offset)` in gauss
+fn px4_params(calibration: &Calibration) -> Vec<(&'static str, f64)> {
+    let (diagonal, off_diagonal) = soft_iron_parts(&calibration.a_1);
+    let gauss = NT_PER_UNIT / 1e5;
+    vec![
+        ("CAL_MAG0_XOFF", calibration.b[0] * gauss),
+        ("CAL_MAG0_YOFF", calibration.b[1] * gauss),
+        ("CAL_MAG0_ZOFF", calibration.b[2] * gauss),
+        ("CAL_MAG0_XSCALE", diagonal[0]),
+        ("CAL_MAG0_YSCALE", diagonal[1]),
+        ("CAL_MAG0_ZSCALE", diagonal[2]),
+        ("CAL_MAG0_XODIAG", off_diagonal[0]),
+        ("CAL_MAG0_YODIAG", off_diagonal[1]),
+        ("CAL_MAG0_ZODIAG", off_diagonal[2]),
+    ]
+}
+
+/// ArduPilot computes `S * (raw + offset)` in milligauss, so offsets change sign
+fn ardupilot_params(calibration: &Calibration) -> Vec<(&'static str, f64)> {
+    let (diagonal, off_diagonal) = soft_iron_parts(&calibration.a_1);
+    let milligauss = NT_PER_UNIT / 100.0;
+    vec![
+        ("COMPASS_OFS_X", -calibration.b[0] * milligauss),
+        ("COMPASS_OFS_Y", -calibration.b[1] * milligauss),
+        ("COMPASS_OFS_Z", -calibration.b[2] * milligauss),
+        ("COMPASS_DIA_X", diagonal[0]),
+        ("COMPASS_DIA_Y", diagonal[1]),
+        ("COMPASS_DIA_Z", diagonal[2]),
+        ("COMPASS_ODI_X", off_diagonal[0]),
+        ("COMPASS_ODI_Y", off_diagonal[1]),
+        ("COMPASS_ODI_Z", off_diagonal[2]),
+    ]
+}
+
+pub fn render(calibration: &Calibration, format: ExportFormat) -> Result<String, NotFinite> {
+    let values = calibration.a_1.iter().chain(calibration.b.iter());
+    if !values.map(|v| *v as f32).all(f32::is_finite) {
+        return Err(NotFinite);
+    }
+    let suffix = match format {
+        ExportFormat::CHeader => "f",
+        _ => "",
+    };
+    let a_1: Vec<String> = calibration
+        .a_1
+        .row_iter()
+        .map(|r| row(r.iter().copied(), suffix))
+        .collect();
+    let b = row(calibration.b.iter().copied(), suffix);
+    let text = match format {
+        ExportFormat::CHeader => format!(
+            "/*\n{} */\n\
+             #ifndef MAG_CALIBRATION_H\n\
+             #define MAG_CALIBRATION_H\n\
+             \n\
+             static const float MAG_A1[3][3] = {{\n    {{{}}},\n    {{{}}},\n    {{{}}},\n}};\n\
+             \n\
+             static const float MAG_B[3] = {{{}}};\n\
+             \n\
+             #endif /* MAG_CALIBRATION_H */\n",
+            comment(calibration, " * "),
+            a_1[0],
+            a_1[1],
+            a_1[2],
+            b,
+        ),
+        ExportFormat::Rust => format!(
+            "{}\n\
+             pub const MAG_A1: [[f32; 3]; 3] = [\n    [{}],\n    [{}],\n    [{}],\n];\n\
+             \n\
+             pub const MAG_B: [f32; 3] = [{}];\n",
+            comment(calibration, "//! "),
+            a_1[0],
+            a_1[1],
+            a_1[2],
+            b,
+        ),
+        ExportFormat::Floats => {
+            format!("{}{}, {}\n", comment(calibration, "# "), a_1.join(", "), b)
+        }
+        ExportFormat::Px4 => {
+            // QGroundControl: vehicle, component, name, value, MAV_PARAM_TYPE_REAL32
+            let mut text = comment(calibration, "# ");
+            for (name, value) in px4_params(calibration) {
+                writeln!(text, "1\t1\t{}\t{}\t9", name, float(value)).expect("writing to String");
+            }
+            text
+        }
+        ExportFormat::ArduPilot => {
+            let mut text = comment(calibration, "# ");
+            for (name, value) in ardupilot_params(calibration) {
+                writeln!(text, "{},{}", name, float(value)).expect("writing to String");
+            }
+            text
+        }
+    };
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    fn calibration() -> Calibration {
+        Calibration {
+            a_1: Matrix3::new(1.1, 0.02, 0.04, 0.0, 0.9, 0.06, 0.0, 0.0, 1.0),
+            b: Vector3::new(120.0, -80.0, 40.0),
+            ..Default::default()
+        }
+    }
+
+    fn param(params: &[(&str, f64)], name: &str) -> f64 {
+        params.iter().find(|(n, _)| *n == name).unwrap().1
+    }
+
+    #[test]
+    fn refuses_non_finite_values() {
+        let mut calibration = calibration();
+        calibration.b[1] = f64::NAN;
+        for format in ExportFormat::ALL {
+            assert_eq!(render(&calibration, format), Err(NotFinite));
+        }
+        calibration.b[1] = 1e39;
+        assert_eq!(render(&calibration, ExportFormat::CHeader), Err(NotFinite));
+    }
+
+    #[test]
+    fn soft_iron_parts_uses_symmetric_part() {
+        let (diagonal, off_diagonal) = soft_iron_parts(&calibration().a_1);
+        assert_eq!(diagonal, [1.1, 0.9, 1.0]);
+        // xy, xz, yz averaged with yx, zx, zy
+        assert_eq!(off_diagonal, [0.01, 0.02, 0.03]);
+    }
+
+    #[test]
+    fn px4_params_are_in_gauss() {
+        let params = px4_params(&calibration());
+        let (diagonal, off_diagonal) = soft_iron_parts(&calibration().a_1);
+        for (name, b) in [("X", 0.12), ("Y", -0.08), ("Z", 0.04)] {
+            let offset = param(&params, &format!("CAL_MAG0_{}OFF", name));
+            assert!((offset - b).abs() < 1e-12, "{} {}", name, offset);
+        }
+        for (i, name) in ["X", "Y", "Z"].iter().enumerate() {
+            let scale = format!("CAL_MAG0_{}SCALE", name);
+            assert_eq!(param(&params, &scale), diagonal[i]);
+            let odiag = format!("CAL_MAG0_{}ODIAG", name);
+            assert_eq!(param(&params, &odiag), off_diagonal[i]);
+        }
+    }
+
+    #[test]
+    fn ardupilot_offsets_change_sign() {
+        let params = ardupilot_params(&calibration());
+        assert_eq!(param(&params, "COMPASS_OFS_X"), -120.0);
+        assert_eq!(param(&params, "COMPASS_OFS_Y"), 80.0);
+        assert_eq!(param(&params, "COMPASS_OFS_Z"), -40.0);
+        assert_eq!(param(&params, "COMPASS_DIA_X"), 1.1);
+        assert_eq!(param(&params, "COMPASS_DIA_Y"), 0.9);
+        assert_eq!(param(&params, "COMPASS_DIA_Z"), 1.0);
+        // ODI_X is xy, ODI_Y is xz and ODI_Z is yz
+        assert_eq!(param(&params, "COMPASS_ODI_X"), 0.01);
+        assert_eq!(param(&params, "COMPASS_ODI_Y"), 0.02);
+        assert_eq!(param(&params, "COMPASS_ODI_Z"), 0.03);
+    }
+
+    #[test]
+    fn c_header_is_pinned() {
+        let expected = concat!(
+            "/*\n",
+            " * Magnetometer calibration: calibrated = MAG_A1 * (raw - MAG_B)\n",
+            " */\n",
+            "#ifndef MAG_CALIBRATION_H\n",
+            "#define MAG_CALIBRATION_H\n",
+            "\n",
+            "static const float MAG_A1[3][3] = {\n",
+            "    {1.100000024e0f, 1.999999955e-2f, 3.999999911e-2f},\n",
+            "    {0.000000000e0f, 8.999999762e-1f, 5.999999866e-2f},\n",
+            "    {0.000000000e0f, 0.000000000e0f, 1.000000000e0f},\n",
+            "};\n",
+            "\n",
+            "static const float MAG_B[3] = {1.200000000e2f, -8.000000000e1f, 4.000000000e1f};\n",
+            "\n",
+            "#endif /* MAG_CALIBRATION_H */\n",
+        );
+        assert_eq!(
+            render(&calibration(), ExportFormat::CHeader).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn rust_is_pinned() {
+        let expected = concat!(
+            "//! Magnetometer calibration: calibrated = MAG_A1 * (raw - MAG_B)\n",
+            "\n",
+            "pub const MAG_A1: [[f32; 3]; 3] = [\n",
+            "    [1.100000024e0, 1.999999955e-2, 3.999999911e-2],\n",
+            "    [0.000000000e0, 8.999999762e-1, 5.999999866e-2],\n",
+            "    [0.000000000e0, 0.000000000e0, 1.000000000e0],\n",
+            "];\n",
+            "\n",
+            "pub const MAG_B: [f32; 3] = [1.200000000e2, -8.000000000e1, 4.000000000e1];\n",
+        );
+        assert_eq!(
+            render(&calibration(), ExportFormat::Rust).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn floats_are_pinned() {
+        let expected = concat!(
+            "# Magnetometer calibration: calibrated = MAG_A1 * (raw - MAG_B)\n",
+            "1.100000024e0, 1.999999955e-2, 3.999999911e-2, ",
+            "0.000000000e0, 8.999999762e-1, 5.999999866e-2, ",
+            "0.000000000e0, 0.000000000e0, 1.000000000e0, ",
+            "1.200000000e2, -8.000000000e1, 4.000000000e1\n",
+        );
+        assert_eq!(
+            render(&calibration(), ExportFormat::Floats).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn px4_is_pinned() {
+        let expected = concat!(
+            "# Magnetometer calibration: calibrated = MAG_A1 * (raw - MAG_B)\n",
+            "1\t1\tCAL_MAG0_XOFF\t1.199999973e-1\t9\n",
+            "1\t1\tCAL_MAG0_YOFF\t-7.999999821e-2\t9\n",
+            "1\t1\tCAL_MAG0_ZOFF\t3.999999911e-2\t9\n",
+            "1\t1\tCAL_MAG0_XSCALE\t1.100000024e0\t9\n",
+            "1\t1\tCAL_MAG0_YSCALE\t8.999999762e-1\t9\n",
+            "1\t1\tCAL_MAG0_ZSCALE\t1.000000000e0\t9\n",
+            "1\t1\tCAL_MAG0_XODIAG\t9.999999776e-3\t9\n",
+            "1\t1\tCAL_MAG0_YODIAG\t1.999999955e-2\t9\n",
+            "1\t1\tCAL_MAG0_ZODIAG\t2.999999933e-2\t9\n",
+        );
+        assert_eq!(render(&calibration(), ExportFormat::Px4).unwrap(), expected);
+    }
+
+    #[test]
+    fn ardupilot_is_pinned() {
+        let expected = concat!(
+            "# Magnetometer calibration: calibrated = MAG_A1 * (raw - MAG_B)\n",
+            "COMPASS_OFS_X,-1.200000000e2\n",
+            "COMPASS_OFS_Y,8.000000000e1\n",
+            "COMPASS_OFS_Z,-4.000000000e1\n",
+            "COMPASS_DIA_X,1.100000024e0\n",
+            "COMPASS_DIA_Y,8.999999762e-1\n",
+            "COMPASS_DIA_Z,1.000000000e0\n",
+            "COMPASS_ODI_X,9.999999776e-3\n",
+            "COMPASS_ODI_Y,1.999999955e-2\n",
+            "COMPASS_ODI_Z,2.999999933e-2\n",
+        );
+        assert_eq!(
+            render(&calibration(), ExportFormat::ArduPilot).unwrap(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,55 @@
+//! Ground truth calibration and deterministic noise shared by the tests
+use nalgebra::{Matrix3, Rotation3, Vector3};
+
+/// Field strength the readings are generated for
+pub const FIELD: f64 = 500.0;
+
+/// Symmetric soft iron with axes rotated away from the sensor axes and a hard iron offset
+pub fn truth() -> (Matrix3<f64>, Vector3<f64>) {
+    let r = Rotation3::from_euler_angles(0.3, -0.5, 0.8).into_inner();
+    let a_1 = r * Matrix3::from_diagonal(&Vector3::new(1.2, 0.9, 1.05)) * r.transpose();
+    (a_1, Vector3::new(120.0, -80.0, 40.0))
+}
+
+/// `n` directions spread evenly over the unit sphere
+pub fn sphere(n: usize) -> Vec<Vector3<f64>> {
+    let golden = std::f64::consts::PI * (3.0 - 5f64.sqrt());
+    (0..n)
+        .map(|i| {
+            let z = 1.0 - 2.0 * (i as f64 + 0.5) / n as f64;
+            let r = (1.0 - z * z).sqrt();
+            let phi = golden * i as f64;
+            Vector3::new(r * phi.cos(), r * phi.sin(), z)
+        })
+        .collect()
+}
+
+/// Raw readings that `a_1` and `b` map onto the sphere of radius [`FIELD`]
+pub fn raw(directions: &[Vector3<f64>], a_1: &Matrix3<f64>, b: &Vector3<f64>) -> Vec<[f64; 3]> {
+    let a = a_1.try_inverse().unwrap();
+    directions
+        .iter()
+        .map(|u| (a * u * FIELD + b).into())
+        .collect()
+}
+
+/// Uniform noise in -1 to 1 from xorshift64, the same for every run
+pub struct Noise(u64);
+
+impl Noise {
+    /// `seed` must not be zero
+    pub fn new(seed: u64) -> Self {
+        Noise(seed)
+    }
+
+    pub fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % (1 << 20)) as f64 / (1 << 19) as f64 - 1.0
+    }
+
+    pub fn vector(&mut self) -> Vector3<f64> {
+        Vector3::new(self.next(), self.next(), self.next())
+    }
+}
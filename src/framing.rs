@@ -0,0 +1,211 @@
+//! Splits a byte stream into delimited frames
+//!
+//! Reads from a serial port end wherever the OS returned, so one read may hold half a
+//! line or several. [`LineFramer`] keeps the unfinished tail until its delimiter arrives.
+//! It splits [binary](crate::binary) frames the same way with NUL as the delimiter.
+use std::fmt;
+
+/// Longest frame kept by default, the test firmware writes lines of about 300 bytes
+pub const MAX_FRAME: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// Frame is not valid UTF-8, holds its bytes
+    NotUtf8(Vec<u8>),
+    /// No delimiter within the maximum length, holds the number of bytes dropped
+    Oversized(usize),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::NotUtf8(bytes) => write!(f, "{} bytes of invalid UTF-8", bytes.len()),
+            FrameError::Oversized(len) => write!(f, "{} bytes without a delimiter", len),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Accumulates bytes and yields the text between delimiters
+#[derive(Debug, Clone)]
+pub struct LineFramer {
+    delimiter: u8,
+    max_len: usize,
+    buffer: Vec<u8>,
+    /// Bytes dropped from an oversized frame whose delimiter has not arrived yet
+    discarded: Option<usize>,
+}
+
+impl Default for LineFramer {
+    fn default() -> Self {
+        LineFramer::new(b'\n', MAX_FRAME)
+    }
+}
+
+impl LineFramer {
+    pub fn new(delimiter: u8, max_len: usize) -> Self {
+        LineFramer {
+            delimiter,
+            max_len,
+            buffer: Vec::new(),
+            discarded: None,
+        }
+    }
+
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+
+    /// Adds bytes as they were read, returns the lines they completed
+    ///
+    /// A trailing `\r` is removed and empty lines are skipped.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Result<String, FrameError>> {
+        self.push_frames(bytes)
+            .into_iter()
+            .filter_map(|frame| {
+                let mut frame = match frame {
+                    Ok(frame) => frame,
+                    Err(e) => return Some(Err(e)),
+                };
+                if frame.last() == Some(&b'\r') {
+                    frame.pop();
+                }
+                if frame.is_empty() {
+                    return None;
+                }
+                Some(String::from_utf8(frame).map_err(|e| FrameError::NotUtf8(e.into_bytes())))
+            })
+            .collect()
+    }
+
+    /// Like [`LineFramer::push`] for binary frames, which are returned as they are
+    ///
+    /// Empty frames are skipped. An oversized frame is reported once its delimiter arrives.
+    pub fn push_frames(&mut self, bytes: &[u8]) -> Vec<Result<Vec<u8>, FrameError>> {
+        let mut frames = vec![];
+        for chunk in bytes.split_inclusive(|b| *b == self.delimiter) {
+            let complete = chunk.last() == Some(&self.delimiter);
+            let chunk = if complete {
+                &chunk[..chunk.len() - 1]
+            } else {
+                chunk
+            };
+            match self.discarded.as_mut() {
+                Some(dropped) => *dropped += chunk.len(),
+                None if self.buffer.len() + chunk.len() > self.max_len => {
+                    self.discarded = Some(self.buffer.len() + chunk.len());
+                    self.buffer.clear();
+                }
+                None => self.buffer.extend_from_slice(chunk),
+            }
+            if !complete {
+                continue;
+            }
+            if let Some(dropped) = self.discarded.take() {
+                frames.push(Err(FrameError::Oversized(dropped)));
+                continue;
+            }
+            let frame = std::mem::take(&mut self.buffer);
+            if !frame.is_empty() {
+                frames.push(Ok(frame));
+            }
+        }
+        frames
+    }
+
+    /// Bytes of the unfinished frame
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.discarded = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(frame: &[u8]) -> Result<Vec<u8>, FrameError> {
+        Ok(frame.to_vec())
+    }
+
+    #[test]
+    fn keeps_a_frame_split_across_pushes() {
+        let mut framer = LineFramer::default();
+        assert_eq!(framer.push_frames(b"ab"), vec![]);
+        assert_eq!(framer.pending(), 2);
+        assert_eq!(framer.push_frames(b"c\nd"), vec![ok(b"abc")]);
+        assert_eq!(framer.pending(), 1);
+        assert_eq!(framer.push_frames(b"e\n"), vec![ok(b"de")]);
+        assert_eq!(framer.pending(), 0);
+    }
+
+    #[test]
+    fn returns_every_frame_of_one_push() {
+        let mut framer = LineFramer::default();
+        let frames = framer.push_frames(b"a\nbc\n\nd\ne");
+        assert_eq!(frames, vec![ok(b"a"), ok(b"bc"), ok(b"d")]);
+        assert_eq!(framer.pending(), 1);
+    }
+
+    #[test]
+    fn strips_carriage_returns_from_lines() {
+        let mut framer = LineFramer::default();
+        let lines = framer.push(b"one\r\n\r\ntwo\r\nthree\n");
+        assert_eq!(
+            lines,
+            vec![
+                Ok("one".to_string()),
+                Ok("two".to_string()),
+                Ok("three".to_string())
+            ]
+        );
+        // Binary frames keep the byte
+        assert_eq!(framer.push_frames(b"\r\n"), vec![ok(b"\r")]);
+    }
+
+    #[test]
+    fn drops_an_oversized_frame_until_its_delimiter() {
+        let mut framer = LineFramer::new(b'\n', 4);
+        assert_eq!(framer.push_frames(b"abcd\n"), vec![ok(b"abcd")]);
+        assert_eq!(framer.push_frames(b"abc"), vec![]);
+        assert_eq!(framer.push_frames(b"def"), vec![]);
+        assert_eq!(framer.pending(), 0);
+        assert_eq!(
+            framer.push_frames(b"gh\nok\n"),
+            vec![Err(FrameError::Oversized(8)), ok(b"ok")]
+        );
+        assert_eq!(
+            framer.push(b"0123456789\nfine\n"),
+            vec![Err(FrameError::Oversized(10)), Ok("fine".to_string())]
+        );
+    }
+
+    #[test]
+    fn reports_lines_that_are_not_utf8() {
+        let mut framer = LineFramer::default();
+        assert_eq!(
+            framer.push(b"\xff\xfe\r\nok\n"),
+            vec![
+                Err(FrameError::NotUtf8(vec![0xff, 0xfe])),
+                Ok("ok".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_at_any_delimiter() {
+        let mut framer = LineFramer::new(0, MAX_FRAME);
+        assert_eq!(
+            framer.push_frames(b"a\n\0\0b\0"),
+            vec![ok(b"a\n"), ok(b"b")]
+        );
+        framer.push_frames(b"partial");
+        framer.clear();
+        assert_eq!(framer.pending(), 0);
+    }
+}
@@ -0,0 +1,299 @@
+//! Turns serial reads into complete frames and counts what could not be used
+//!
+//! Samples come as JSON lines or as [binary](bevy_mag::binary) frames. With
+//! [`Protocol::Auto`] each port keeps what it received until one of them yields a valid
+//! sample.
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use clap::ValueEnum;
+
+use bevy_mag::binary;
+use bevy_mag::framing::{FrameError, LineFramer, MAX_FRAME};
+use bevy_mag::Sample;
+
+use crate::diagnostics::Diagnostics;
+use crate::serial::SerialReadEvent;
+
+/// Encoding of the samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Protocol {
+    /// Whichever yields a valid sample first
+    Auto,
+    /// A JSON sample per line
+    Json,
+    /// COBS framed samples with CRC
+    Binary,
+}
+
+/// A complete frame
+pub enum FrameRead {
+    /// Line of text, without its delimiter
+    Line(String),
+    /// Sample decoded from a binary frame
+    Sample(Box<Sample>),
+}
+
+const DELIMITERS: [(u8, &str); 3] = [(b'\n', "LF"), (b'\r', "CR"), (0, "NUL")];
+
+/// Bytes to look at before giving up detection and assuming JSON
+const DETECT_WITHIN: usize = 4 * MAX_FRAME;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameCounters {
+    pub frames: u64,
+    pub not_utf8: u64,
+    pub oversized: u64,
+}
+
+impl FrameCounters {
+    fn count(&mut self, e: &FrameError) {
+        match e {
+            FrameError::NotUtf8(_) => self.not_utf8 += 1,
+            FrameError::Oversized(_) => self.oversized += 1,
+        }
+    }
+}
+
+/// Frames followed by a delimiter, without the unfinished last
+///
+/// The first frame is kept although it may be the tail of one sent before the port was
+/// opened: such a tail neither parses as a JSON sample nor has the length of a binary one,
+/// and replay sends one line per read, so the first line is often all there is.
+fn complete_frames(bytes: &[u8], delimiter: u8) -> impl Iterator<Item = &[u8]> {
+    let mut parts: Vec<&[u8]> = bytes.split(move |b| *b == delimiter).collect();
+    parts.pop();
+    parts.into_iter()
+}
+
+/// Protocol of the first valid sample in `bytes`
+fn detect(bytes: &[u8], delimiter: u8) -> Option<Protocol> {
+    if complete_frames(bytes, binary::DELIMITER).any(|f| binary::decode(f).is_ok()) {
+        return Some(Protocol::Binary);
+    }
+    let is_sample = |f: &[u8]| {
+        let f = f.strip_suffix(b"\r").unwrap_or(f);
+        serde_json::from_slice::<Sample>(f).is_ok()
+    };
+    if complete_frames(bytes, delimiter).any(is_sample) {
+        return Some(Protocol::Json);
+    }
+    None
+}
+
+/// Command replies in a binary stream are COBS framed text without CRC
+fn binary_reply(frame: &[u8]) -> Option<String> {
+    let text = String::from_utf8(binary::cobs_decode(frame).ok()?).ok()?;
+    text.starts_with('$').then(|| text.trim_end().to_string())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Framing of one port, so a partial frame of one port is not continued by another
+struct PortFraming {
+    /// [`Protocol::Auto`] until detected
+    protocol: Protocol,
+    framer: LineFramer,
+    /// Bytes received while detecting
+    received: Vec<u8>,
+}
+
+impl PortFraming {
+    fn new(protocol: Protocol, delimiter: u8) -> Self {
+        let delimiter = match protocol {
+            Protocol::Binary => binary::DELIMITER,
+            Protocol::Auto | Protocol::Json => delimiter,
+        };
+        PortFraming {
+            protocol,
+            framer: LineFramer::new(delimiter, MAX_FRAME),
+            received: vec![],
+        }
+    }
+
+    /// Bytes to frame, all received so far once the protocol is known
+    fn detect(&mut self, bytes: &[u8], delimiter: u8) -> Option<Vec<u8>> {
+        self.received.extend_from_slice(bytes);
+        let protocol = detect(&self.received, delimiter)
+            .or((self.received.len() > DETECT_WITHIN).then_some(Protocol::Json))?;
+        let received = std::mem::take(&mut self.received);
+        *self = PortFraming::new(protocol, delimiter);
+        Some(received)
+    }
+}
+
+#[derive(Resource)]
+pub struct Framing {
+    protocol: Protocol,
+    /// Delimiter of JSON lines
+    delimiter: u8,
+    ports: HashMap<String, PortFraming>,
+    pub counters: FrameCounters,
+}
+
+impl Framing {
+    pub fn new(protocol: Protocol) -> Self {
+        Framing {
+            protocol,
+            delimiter: b'\n',
+            ports: HashMap::new(),
+            counters: FrameCounters::default(),
+        }
+    }
+}
+
+pub struct FramingPlugin {
+    protocol: Protocol,
+}
+
+impl FramingPlugin {
+    pub fn new(protocol: Protocol) -> Self {
+        FramingPlugin { protocol }
+    }
+}
+
+impl Plugin for FramingPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Framing::new(self.protocol))
+            .add_event::<FrameRead>()
+            .add_system(split_frames)
+            .add_system(draw_framing_ui);
+    }
+}
+
+fn split_frames(
+    mut framing: ResMut<Framing>,
+    mut diagnostics: ResMut<Diagnostics>,
+    mut ev_serial: EventReader<SerialReadEvent>,
+    mut ev_frames: EventWriter<FrameRead>,
+) {
+    let framing = framing.as_mut();
+    for SerialReadEvent(label, buffer) in ev_serial.iter() {
+        let port = framing
+            .ports
+            .entry(label.clone())
+            .or_insert_with(|| PortFraming::new(framing.protocol, framing.delimiter));
+        let bytes = match port.protocol {
+            Protocol::Auto => match port.detect(buffer, framing.delimiter) {
+                Some(bytes) => bytes,
+                None => continue,
+            },
+            Protocol::Json | Protocol::Binary => buffer.clone(),
+        };
+        if port.protocol == Protocol::Binary {
+            for frame in port.framer.push_frames(&bytes) {
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        framing.counters.count(&e);
+                        continue;
+                    }
+                };
+                framing.counters.frames += 1;
+                match binary::decode(&frame) {
+                    Ok(sample) => ev_frames.send(FrameRead::Sample(Box::new(sample))),
+                    Err(e) => match binary_reply(&frame) {
+                        Some(reply) => ev_frames.send(FrameRead::Line(reply)),
+                        None => diagnostics.rejected(&hex(&frame), e),
+                    },
+                }
+            }
+            continue;
+        }
+        for line in port.framer.push(&bytes) {
+            match line {
+                Ok(line) => {
+                    framing.counters.frames += 1;
+                    ev_frames.send(FrameRead::Line(line));
+                }
+                Err(e) => framing.counters.count(&e),
+            }
+        }
+    }
+}
+
+fn draw_framing_ui(mut contexts: EguiContexts, mut framing: ResMut<Framing>) {
+    egui::Window::new("Framing")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            let mut protocol = framing.protocol;
+            egui::ComboBox::from_label("Protocol")
+                .selected_text(format!("{:?}", protocol))
+                .show_ui(ui, |ui| {
+                    for p in Protocol::value_variants() {
+                        ui.selectable_value(&mut protocol, *p, format!("{:?}", p));
+                    }
+                });
+            let name = |delimiter: u8| {
+                DELIMITERS
+                    .iter()
+                    .find(|(d, _)| *d == delimiter)
+                    .map_or("?", |(_, name)| name)
+            };
+            let mut delimiter = framing.delimiter;
+            egui::ComboBox::from_label("Line delimiter")
+                .selected_text(name(delimiter))
+                .show_ui(ui, |ui| {
+                    for (d, name) in DELIMITERS {
+                        ui.selectable_value(&mut delimiter, d, name);
+                    }
+                });
+            if protocol != framing.protocol || delimiter != framing.delimiter {
+                framing.protocol = protocol;
+                framing.delimiter = delimiter;
+                framing.ports.clear();
+            }
+            for (label, port) in &framing.ports {
+                let detected = match port.protocol {
+                    Protocol::Auto => "detecting".to_string(),
+                    p => format!("{:?}", p),
+                };
+                ui.label(format!("{}: {}", label, detected));
+            }
+            let c = framing.counters;
+            egui::Grid::new("frame counters").show(ui, |ui| {
+                for (label, count) in [
+                    ("Frames", c.frames),
+                    ("Not UTF-8", c.not_utf8),
+                    ("Oversized", c.oversized),
+                ] {
+                    ui.label(label);
+                    ui.label(count.to_string());
+                    ui.end_row();
+                }
+            });
+            let pending: usize = framing
+                .ports
+                .values()
+                .map(|p| p.framer.pending() + p.received.len())
+                .sum();
+            ui.label(format!("{} bytes waiting for a delimiter", pending));
+            if ui.button("Reset counters").clicked() {
+                framing.counters = FrameCounters::default();
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_from_the_first_complete_frame() {
+        let line = serde_json::to_string(&Sample::default()).unwrap() + "\n";
+        assert_eq!(detect(line.as_bytes(), b'\n'), Some(Protocol::Json));
+        let frame = binary::encode(&Sample::default());
+        assert_eq!(detect(&frame, b'\n'), Some(Protocol::Binary));
+        // A tail of a frame sent before the port was opened is not mistaken for a sample
+        assert_eq!(detect(&line.as_bytes()[10..], b'\n'), None);
+        assert_eq!(detect(&frame[10..], b'\n'), None);
+        assert_eq!(detect(&line.as_bytes()[..10], b'\n'), None);
+    }
+}
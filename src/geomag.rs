@@ -0,0 +1,319 @@
+//! World Magnetic Model evaluator
+//!
+//! Implements the spherical harmonic synthesis from the WMM technical report
+//! (https://www.ncei.noaa.gov/products/world-magnetic-model). Coefficients of
+//! WMM-2025 are bundled, newer `WMM.COF` files can be loaded with [`MagneticModel::parse`].
+use std::fmt;
+
+/// Coefficients shipped with the application
+const WMM_COF: &str = include_str!("WMM.COF");
+
+/// WGS-84 semi-major axis, km
+const WGS84_A: f64 = 6378.137;
+/// WGS-84 flattening
+const WGS84_F: f64 = 1.0 / 298.257223563;
+/// Geomagnetic reference radius, km
+const RE: f64 = 6371.2;
+
+/// Error while reading a coefficient file
+#[derive(Debug, Clone, PartialEq)]
+pub struct CofError {
+    pub line: usize,
+}
+
+impl fmt::Display for CofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed coefficient file at line {}", self.line)
+    }
+}
+
+impl std::error::Error for CofError {}
+
+/// Gauss coefficients of a main field model and its secular variation
+#[derive(Debug, Clone)]
+pub struct MagneticModel {
+    pub name: String,
+    /// Decimal year the coefficients refer to
+    pub epoch: f64,
+    degree: usize,
+    g: Vec<Vec<f64>>,
+    h: Vec<Vec<f64>>,
+    g_dot: Vec<Vec<f64>>,
+    h_dot: Vec<Vec<f64>>,
+}
+
+/// Magnetic field components at a point, nT and degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MagneticField {
+    /// North component
+    pub x: f64,
+    /// East component
+    pub y: f64,
+    /// Down component
+    pub z: f64,
+    /// Horizontal intensity
+    pub h: f64,
+    /// Total intensity
+    pub f: f64,
+    /// Inclination (dip), positive down
+    pub inclination: f64,
+    /// Declination, positive east
+    pub declination: f64,
+}
+
+impl MagneticModel {
+    /// Parses the NOAA `WMM.COF` format
+    pub fn parse(text: &str) -> Result<Self, CofError> {
+        let mut lines = text.lines().enumerate();
+        let (_, header) = lines.next().ok_or(CofError { line: 1 })?;
+        let mut header = header.split_whitespace();
+        let epoch = header
+            .next()
+            .and_then(|e| e.parse::<f64>().ok())
+            .ok_or(CofError { line: 1 })?;
+        let name = header.next().unwrap_or("unknown").to_string();
+
+        let mut rows = vec![];
+        for (i, line) in lines {
+            if line.starts_with("9999") {
+                break;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let error = CofError { line: i + 1 };
+            if fields.len() < 6 {
+                return Err(error);
+            }
+            let n: usize = fields[0].parse().map_err(|_| error.clone())?;
+            let m: usize = fields[1].parse().map_err(|_| error.clone())?;
+            let mut values = [0.0; 4];
+            for (v, field) in values.iter_mut().zip(&fields[2..6]) {
+                *v = field.parse().map_err(|_| error.clone())?;
+            }
+            if n == 0 || m > n {
+                return Err(error);
+            }
+            rows.push((n, m, values));
+        }
+
+        let degree = rows
+            .iter()
+            .map(|(n, _, _)| *n)
+            .max()
+            .ok_or(CofError { line: 2 })?;
+        let zeros = vec![vec![0.0; degree + 1]; degree + 1];
+        let mut model = MagneticModel {
+            name,
+            epoch,
+            degree,
+            g: zeros.clone(),
+            h: zeros.clone(),
+            g_dot: zeros.clone(),
+            h_dot: zeros,
+        };
+        for (n, m, [g, h, g_dot, h_dot]) in rows {
+            model.g[n][m] = g;
+            model.h[n][m] = h;
+            model.g_dot[n][m] = g_dot;
+            model.h_dot[n][m] = h_dot;
+        }
+        Ok(model)
+    }
+
+    /// Years after the epoch for which the model is considered valid
+    pub fn is_valid(&self, year: f64) -> bool {
+        year >= self.epoch && year < self.epoch + 5.0
+    }
+
+    /// Evaluates the field at geodetic `latitude`, `longitude` (degrees),
+    /// `altitude` above the WGS-84 ellipsoid (km) and decimal `year`
+    pub fn field(&self, latitude: f64, longitude: f64, altitude: f64, year: f64) -> MagneticField {
+        let dt = year - self.epoch;
+        let lat = latitude.to_radians();
+        let lon = longitude.to_radians();
+
+        // Geodetic to geocentric spherical coordinates
+        let e2 = WGS84_F * (2.0 - WGS84_F);
+        let rc = WGS84_A / (1.0 - e2 * lat.sin().powi(2)).sqrt();
+        let p = (rc + altitude) * lat.cos();
+        let z = (rc * (1.0 - e2) + altitude) * lat.sin();
+        let r = (p * p + z * z).sqrt();
+        let lat_c = (z / r).asin();
+
+        // Colatitude, clamped away from the poles where B_phi is singular
+        let theta = std::f64::consts::FRAC_PI_2 - lat_c;
+        let sin_t = theta.sin().max(1e-10);
+        let cos_t = theta.cos();
+
+        let (p_nm, dp_nm) = self.legendre(sin_t, cos_t);
+
+        let mut b_r = 0.0;
+        let mut b_theta = 0.0;
+        let mut b_phi = 0.0;
+        let ratio = RE / r;
+        let mut ratio_n = ratio * ratio;
+        for n in 1..=self.degree {
+            ratio_n *= ratio;
+            for m in 0..=n {
+                let g = self.g[n][m] + dt * self.g_dot[n][m];
+                let h = self.h[n][m] + dt * self.h_dot[n][m];
+                let (sin_ml, cos_ml) = (m as f64 * lon).sin_cos();
+                let gh = g * cos_ml + h * sin_ml;
+                b_r += (n as f64 + 1.0) * ratio_n * gh * p_nm[n][m];
+                b_theta -= ratio_n * gh * dp_nm[n][m];
+                b_phi -= ratio_n * m as f64 * (-g * sin_ml + h * cos_ml) * p_nm[n][m];
+            }
+        }
+        b_phi /= sin_t;
+
+        // Rotate from geocentric to geodetic frame
+        let x_c = -b_theta;
+        let z_c = -b_r;
+        let psi = lat_c - lat;
+        let x = x_c * psi.cos() - z_c * psi.sin();
+        let y = b_phi;
+        let z = x_c * psi.sin() + z_c * psi.cos();
+
+        let h = x.hypot(y);
+        MagneticField {
+            x,
+            y,
+            z,
+            h,
+            f: h.hypot(z),
+            inclination: z.atan2(h).to_degrees(),
+            declination: y.atan2(x).to_degrees(),
+        }
+    }
+
+    /// Schmidt semi-normalised associated Legendre functions and their
+    /// derivatives with respect to colatitude
+    fn legendre(&self, sin_t: f64, cos_t: f64) -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+        let size = self.degree + 1;
+        let mut p = vec![vec![0.0; size]; size];
+        let mut dp = vec![vec![0.0; size]; size];
+        p[0][0] = 1.0;
+        for n in 1..size {
+            for m in 0..=n {
+                if n == m {
+                    p[n][m] = sin_t * p[n - 1][m - 1];
+                    dp[n][m] = sin_t * dp[n - 1][m - 1] + cos_t * p[n - 1][m - 1];
+                } else if n == 1 {
+                    p[n][m] = cos_t * p[n - 1][m];
+                    dp[n][m] = cos_t * dp[n - 1][m] - sin_t * p[n - 1][m];
+                } else {
+                    let (nf, mf) = (n as f64, m as f64);
+                    let k = ((nf - 1.0).powi(2) - mf * mf) / ((2.0 * nf - 1.0) * (2.0 * nf - 3.0));
+                    p[n][m] = cos_t * p[n - 1][m] - k * p[n - 2][m];
+                    dp[n][m] = cos_t * dp[n - 1][m] - sin_t * p[n - 1][m] - k * dp[n - 2][m];
+                }
+            }
+        }
+
+        // Gauss to Schmidt normalisation
+        let mut s = 1.0;
+        for n in 1..size {
+            let nf = n as f64;
+            s *= (2.0 * nf - 1.0) / nf;
+            let mut s_m = s;
+            for m in 0..=n {
+                if m > 0 {
+                    let mf = m as f64;
+                    let delta = if m == 1 { 2.0 } else { 1.0 };
+                    s_m *= ((nf - mf + 1.0) * delta / (nf + mf)).sqrt();
+                }
+                p[n][m] *= s_m;
+                dp[n][m] *= s_m;
+            }
+        }
+        (p, dp)
+    }
+}
+
+/// Returns the bundled World Magnetic Model
+pub fn wmm() -> MagneticModel {
+    MagneticModel::parse(WMM_COF).expect("bundled WMM.COF to be valid")
+}
+
+/// Current date as a decimal year
+pub fn current_year() -> f64 {
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    1970.0 + since_epoch.as_secs_f64() / (365.2425 * 86400.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Year, altitude (km), latitude, longitude, F (nT), I, D (degrees)
+    type TestPoint = (f64, f64, f64, f64, f64, f64, f64);
+
+    /// Test values published with WMM-2025
+    const WMM2025_TEST_VALUES: [TestPoint; 12] = [
+        (2025.0, 0.0, 80.0, 0.0, 55178.5, 83.21, 1.28),
+        (2025.0, 0.0, 0.0, 120.0, 41064.3, -14.93, -0.16),
+        (2025.0, 0.0, -80.0, 240.0, 54698.2, -72.00, 68.78),
+        (2025.0, 100.0, 80.0, 0.0, 52964.9, 83.26, 0.85),
+        (2025.0, 100.0, 0.0, 120.0, 39032.1, -15.08, -0.15),
+        (2025.0, 100.0, -80.0, 240.0, 52035.0, -72.19, 68.21),
+        (2027.5, 0.0, 80.0, 0.0, 55253.9, 83.24, 2.59),
+        (2027.5, 0.0, 0.0, 120.0, 41036.9, -14.65, -0.24),
+        (2027.5, 0.0, -80.0, 240.0, 54474.2, -71.92, 68.49),
+        (2027.5, 100.0, 80.0, 0.0, 53034.3, 83.29, 2.16),
+        (2027.5, 100.0, 0.0, 120.0, 39007.4, -14.81, -0.23),
+        (2027.5, 100.0, -80.0, 240.0, 51825.7, -72.10, 67.93),
+    ];
+
+    /// Test values published with WMM-2020, checks the evaluator against an older model
+    const WMM2020_TEST_VALUES: [TestPoint; 12] = [
+        (2020.0, 0.0, 80.0, 0.0, 55000.1, 83.14, -1.28),
+        (2020.0, 0.0, 0.0, 120.0, 41104.9, -15.42, 0.16),
+        (2020.0, 0.0, -80.0, 240.0, 55120.6, -72.20, 69.36),
+        (2020.0, 100.0, 80.0, 0.0, 52802.0, 83.19, -1.70),
+        (2020.0, 100.0, 0.0, 120.0, 39067.3, -15.55, 0.16),
+        (2020.0, 100.0, -80.0, 240.0, 52430.6, -72.37, 68.78),
+        (2022.5, 0.0, 80.0, 0.0, 55101.7, 83.19, 0.01),
+        (2022.5, 0.0, 0.0, 120.0, 41130.5, -15.24, -0.06),
+        (2022.5, 0.0, -80.0, 240.0, 54912.1, -72.09, 69.13),
+        (2022.5, 100.0, 80.0, 0.0, 52894.5, 83.24, -0.41),
+        (2022.5, 100.0, 0.0, 120.0, 39092.4, -15.37, -0.05),
+        (2022.5, 100.0, -80.0, 240.0, 52235.4, -72.27, 68.55),
+    ];
+
+    /// Values are published rounded to 0.1 nT and 0.01 degrees
+    fn check(model: &MagneticModel, points: &[TestPoint]) {
+        for &(year, alt, lat, lon, f, i, d) in points {
+            let field = model.field(lat, lon, alt, year);
+            let at = format!("{} at {} km, {} {}: {:?}", year, alt, lat, lon, field);
+            assert!((field.f - f).abs() <= 0.1, "F {}", at);
+            assert!((field.inclination - i).abs() <= 0.01, "I {}", at);
+            assert!((field.declination - d).abs() <= 0.01, "D {}", at);
+        }
+    }
+
+    #[test]
+    fn bundled_model_matches_test_values() {
+        let model = wmm();
+        assert_eq!(model.name, "WMM-2025");
+        check(&model, &WMM2025_TEST_VALUES);
+    }
+
+    #[test]
+    fn evaluator_matches_wmm2020_test_values() {
+        let model = MagneticModel::parse(include_str!("WMM2020.COF")).unwrap();
+        check(&model, &WMM2020_TEST_VALUES);
+    }
+
+    #[test]
+    fn bundled_model_is_valid_until_2030() {
+        let model = wmm();
+        assert!(model.is_valid(2025.0));
+        assert!(model.is_valid(2029.9));
+        assert!(!model.is_valid(2030.0));
+        assert!(!model.is_valid(2024.9));
+    }
+}
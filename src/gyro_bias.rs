@@ -0,0 +1,80 @@
+//! Estimates the gyroscope bias whenever the board rests
+//!
+//! The estimate keeps improving in the background, "Apply" copies it into the calibration.
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use bevy_mag::imu::{GyroBiasEstimator, GyroCalibration};
+use bevy_mag::{Calibration, SampleRead};
+
+#[derive(Resource, Default, Deref, DerefMut)]
+struct GyroBias(GyroBiasEstimator);
+
+pub struct GyroBiasPlugin;
+
+impl Plugin for GyroBiasPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GyroBias>()
+            .add_system(estimate_bias)
+            .add_system(draw_gyro_ui);
+    }
+}
+
+fn estimate_bias(mut bias: ResMut<GyroBias>, mut ev_samples: EventReader<SampleRead>) {
+    for SampleRead { sample, .. } in ev_samples.iter() {
+        bias.push(&sample.accel, &sample.gyro, sample.dt);
+    }
+}
+
+fn show(ui: &mut egui::Ui, gyro: &GyroCalibration) {
+    let b = gyro.bias;
+    let n = gyro.noise_density;
+    ui.monospace(format!("bias  {:10.4} {:10.4} {:10.4}", b[0], b[1], b[2]));
+    ui.monospace(format!(
+        "noise {:10.4} {:10.4} {:10.4} /√Hz",
+        n[0], n[1], n[2]
+    ));
+}
+
+fn draw_gyro_ui(
+    mut contexts: EguiContexts,
+    mut bias: ResMut<GyroBias>,
+    mut calibration: ResMut<Calibration>,
+) {
+    egui::Window::new("Gyroscope")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            if bias.is_still() {
+                ui.label("Still");
+            } else {
+                ui.colored_label(egui::Color32::YELLOW, "Moving");
+            }
+            ui.label(format!("{} still samples", bias.count()));
+            let estimate = bias.estimate();
+            match &estimate {
+                Some(gyro) => show(ui, gyro),
+                None => {
+                    ui.label("Leave the board at rest to estimate the bias");
+                }
+            }
+            ui.horizontal(|ui| {
+                if ui
+                    .add_enabled(estimate.is_some(), egui::Button::new("Apply"))
+                    .clicked()
+                {
+                    calibration.gyro = estimate;
+                }
+                if ui.button("Reset").clicked() {
+                    bias.clear();
+                }
+            });
+            if let Some(gyro) = calibration.gyro {
+                ui.separator();
+                ui.label("Applied");
+                show(ui, &gyro);
+                if ui.button("Clear").clicked() {
+                    calibration.gyro = None;
+                }
+            }
+        });
+}
@@ -0,0 +1,378 @@
+//! Accelerometer and gyroscope calibration, stillness detection
+//!
+//! The six-position calibration places the board on each face, so the mean reading of
+//! every pose should be `±g` along one axis. Bias, scale and misalignment follow from a
+//! linear least squares fit of the means to these references.
+//!
+//! The gyroscope should read zero whenever the board rests, its bias and noise are
+//! estimated from the windows [`Stillness`] considers still.
+use std::collections::VecDeque;
+
+use nalgebra::{Matrix3, Matrix4x3, OMatrix, Vector3, U3, U4, U6};
+
+use crate::math::FitError;
+
+/// Board faces in the order the six-position calibration asks for them, as the axis
+/// pointing up and its sign
+pub const POSES: [(usize, f64); 6] = [
+    (2, 1.0),
+    (2, -1.0),
+    (0, 1.0),
+    (0, -1.0),
+    (1, 1.0),
+    (1, -1.0),
+];
+
+/// Human readable name of a pose, e.g. `+Z up`
+pub fn pose_name((axis, sign): (usize, f64)) -> String {
+    let sign = if sign > 0.0 { '+' } else { '-' };
+    format!("{}{} up", sign, ['X', 'Y', 'Z'][axis])
+}
+
+/// Pose of the board if one axis clearly points up in `accel`
+pub fn pose_of(accel: &[f32; 3]) -> Option<(usize, f64)> {
+    let v = Vector3::from(accel.map(|c| c as f64));
+    let (axis, max) = v.abs().argmax();
+    // Within about 25 degrees of the axis
+    if max < 0.9 * v.norm() {
+        return None;
+    }
+    Some((axis, v[axis].signum()))
+}
+
+/// Calibrated is `a * (raw - bias)`, in the units of the raw readings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccelCalibration {
+    /// Scale and misalignment
+    pub a: Matrix3<f64>,
+    pub bias: Vector3<f64>,
+}
+
+impl AccelCalibration {
+    /// Fits mean readings of the poses in [`POSES`] order
+    ///
+    /// `g` is taken as the mean norm of the readings, so units do not change.
+    pub fn fit(means: &[[f64; 3]; 6]) -> Result<Self, FitError> {
+        let g = means.iter().map(|m| Vector3::from(*m).norm()).sum::<f64>() / 6.0;
+        // raw = K u + bias with u = ±g e_axis, solve for [K^T; bias^T]
+        let u = OMatrix::<f64, U6, U4>::from_fn(|i, j| {
+            let (axis, sign) = POSES[i];
+            match j {
+                3 => 1.0,
+                _ if j == axis => sign * g,
+                _ => 0.0,
+            }
+        });
+        let r = OMatrix::<f64, U6, U3>::from_fn(|i, j| means[i][j]);
+        let x: Matrix4x3<f64> = (u.transpose() * u)
+            .try_inverse()
+            .ok_or(FitError::DegenerateGeometry)?
+            * u.transpose()
+            * r;
+        let k = x.fixed_view::<3, 3>(0, 0).transpose();
+        let a = k.try_inverse().ok_or(FitError::DegenerateGeometry)?;
+        let bias = x.fixed_view::<1, 3>(3, 0).transpose();
+        Ok(AccelCalibration { a, bias })
+    }
+
+    pub fn apply(&self, raw: &[f32; 3]) -> [f32; 3] {
+        let raw = Vector3::from(raw.map(|c| c as f64));
+        (self.a * (raw - self.bias)).cast::<f32>().into()
+    }
+}
+
+/// Tells if the board rests, from the spread of the last accelerometer and gyroscope readings
+#[derive(Debug, Clone)]
+pub struct Stillness {
+    window: VecDeque<(Vector3<f64>, Vector3<f64>)>,
+    size: usize,
+    /// Largest accelerometer standard deviation per axis relative to the mean norm
+    accel_threshold: f64,
+    /// Largest gyroscope standard deviation per axis, in sensor units
+    gyro_threshold: f64,
+}
+
+/// Windows of 50 readings for the gyro bias estimate, the gyro may vary by 0.5 sensor
+/// units; the accelerometer wizard sets its own thresholds
+impl Default for Stillness {
+    fn default() -> Self {
+        Stillness::new(50, 0.01, 0.5)
+    }
+}
+
+/// Mean and variance per axis
+fn moments<'a>(
+    values: impl Iterator<Item = &'a Vector3<f64>> + Clone,
+) -> (Vector3<f64>, Vector3<f64>) {
+    let n = values.clone().count() as f64;
+    let mean = values.clone().sum::<Vector3<f64>>() / n;
+    let variance = values
+        .map(|v| (v - mean).component_mul(&(v - mean)))
+        .sum::<Vector3<f64>>()
+        / n;
+    (mean, variance)
+}
+
+impl Stillness {
+    /// Ignores the gyroscope if `gyro_threshold` is infinite
+    pub fn new(size: usize, accel_threshold: f64, gyro_threshold: f64) -> Self {
+        Stillness {
+            window: VecDeque::with_capacity(size),
+            size,
+            accel_threshold,
+            gyro_threshold,
+        }
+    }
+
+    /// Number of readings a window holds
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Adds a reading, returns whether the board is still
+    pub fn push(&mut self, accel: &[f32; 3], gyro: &[f32; 3]) -> bool {
+        if self.window.len() == self.size {
+            self.window.pop_front();
+        }
+        let v = |r: &[f32; 3]| Vector3::from(r.map(|c| c as f64));
+        self.window.push_back((v(accel), v(gyro)));
+        self.is_still()
+    }
+
+    pub fn is_still(&self) -> bool {
+        if self.window.len() < self.size {
+            return false;
+        }
+        let (accel, accel_variance) = moments(self.window.iter().map(|(a, _)| a));
+        let (_, gyro_variance) = moments(self.window.iter().map(|(_, g)| g));
+        accel_variance.max().sqrt() <= self.accel_threshold * accel.norm()
+            && gyro_variance.max().sqrt() <= self.gyro_threshold
+    }
+
+    /// Gyroscope readings of the current window
+    pub fn gyro(&self) -> impl Iterator<Item = &Vector3<f64>> {
+        self.window.iter().map(|(_, g)| g)
+    }
+
+    pub fn clear(&mut self) {
+        self.window.clear();
+    }
+}
+
+/// Calibrated is `raw - bias`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GyroCalibration {
+    pub bias: Vector3<f64>,
+    /// Standard deviation at rest times `sqrt(dt)`, sensor units per `sqrt(Hz)`
+    pub noise_density: Vector3<f64>,
+}
+
+impl GyroCalibration {
+    pub fn apply(&self, raw: &[f32; 3]) -> [f32; 3] {
+        let raw = Vector3::from(raw.map(|c| c as f64));
+        (raw - self.bias).cast::<f32>().into()
+    }
+}
+
+/// Accumulates gyroscope readings of non-overlapping still windows
+#[derive(Debug, Clone, Default)]
+pub struct GyroBiasEstimator {
+    stillness: Stillness,
+    /// Readings since the last window was added
+    fresh: usize,
+    count: usize,
+    sum: Vector3<f64>,
+    sum_squares: Vector3<f64>,
+    /// Sum of `dt` over all readings, for the noise density
+    dt: f64,
+    readings: usize,
+}
+
+impl GyroBiasEstimator {
+    pub fn new(stillness: Stillness) -> Self {
+        GyroBiasEstimator {
+            stillness,
+            ..Default::default()
+        }
+    }
+
+    /// Adds a reading, returns whether the board is still
+    pub fn push(&mut self, accel: &[f32; 3], gyro: &[f32; 3], dt: f32) -> bool {
+        self.dt += dt as f64;
+        self.readings += 1;
+        self.fresh += 1;
+        let still = self.stillness.push(accel, gyro);
+        if still && self.fresh >= self.stillness.size() {
+            self.fresh = 0;
+            for g in self.stillness.gyro() {
+                self.count += 1;
+                self.sum += g;
+                self.sum_squares += g.component_mul(g);
+            }
+        }
+        still
+    }
+
+    pub fn is_still(&self) -> bool {
+        self.stillness.is_still()
+    }
+
+    /// Number of still readings the estimate is based on
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// `None` until a still window was seen
+    pub fn estimate(&self) -> Option<GyroCalibration> {
+        if self.count == 0 {
+            return None;
+        }
+        let n = self.count as f64;
+        let bias = self.sum / n;
+        let variance = (self.sum_squares / n - bias.component_mul(&bias)).map(|v| v.max(0.0));
+        let dt = self.dt / self.readings as f64;
+        Some(GyroCalibration {
+            bias,
+            noise_density: variance.map(|v| (v * dt).sqrt()),
+        })
+    }
+
+    pub fn clear(&mut self) {
+        *self = GyroBiasEstimator::new(self.stillness.clone());
+        self.stillness.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::Noise;
+
+    /// Raw readings are `k u + bias`
+    fn truth() -> (Matrix3<f64>, Vector3<f64>) {
+        let k = Matrix3::new(1.02, 0.01, -0.02, 0.015, 0.97, 0.005, -0.01, 0.02, 1.05);
+        (k, Vector3::new(0.3, -0.2, 0.5))
+    }
+
+    fn pose_means(k: &Matrix3<f64>, bias: &Vector3<f64>, g: f64) -> [[f64; 3]; 6] {
+        POSES.map(|(axis, sign)| {
+            let mut u = Vector3::zeros();
+            u[axis] = sign * g;
+            (k * u + bias).into()
+        })
+    }
+
+    #[test]
+    fn accel_fit_recovers_bias_scale_and_misalignment() {
+        let (k, bias) = truth();
+        let means = pose_means(&k, &bias, 9.81);
+        let accel = AccelCalibration::fit(&means).unwrap();
+        assert!((accel.bias - bias).norm() < 1e-9, "{}", accel.bias);
+        // g is taken from the readings, so a undoes k up to a common scale
+        let g = means.iter().map(|m| Vector3::from(*m).norm()).sum::<f64>() / 6.0;
+        let error = accel.a * k - Matrix3::identity() * (g / 9.81);
+        assert!(error.norm() < 1e-9, "{}", accel.a * k);
+        for ((axis, sign), mean) in POSES.iter().zip(means) {
+            let calibrated = Vector3::from(accel.apply(&mean.map(|c| c as f32))).cast::<f64>();
+            let mut expected = Vector3::zeros();
+            expected[*axis] = sign * g;
+            assert!((calibrated - expected).norm() < 1e-4, "{}", calibrated);
+        }
+    }
+
+    #[test]
+    fn accel_fit_rejects_poses_that_do_not_differ() {
+        let means = [[0.1, 0.2, 9.8]; 6];
+        assert_eq!(
+            AccelCalibration::fit(&means),
+            Err(FitError::DegenerateGeometry)
+        );
+    }
+
+    #[test]
+    fn stillness_needs_a_full_quiet_window() {
+        let mut stillness = Stillness::new(20, 0.01, 0.5);
+        let mut noise = Noise::new(1);
+        for i in 0..20 {
+            let accel = [0.02 * noise.next() as f32, 0.0, 9.81];
+            let gyro = [0.1 * noise.next() as f32, 0.0, 0.0];
+            assert_eq!(stillness.push(&accel, &gyro), i == 19, "reading {}", i);
+        }
+        assert_eq!(stillness.gyro().count(), 20);
+        stillness.clear();
+        assert!(!stillness.is_still());
+    }
+
+    #[test]
+    fn stillness_rejects_motion_on_either_sensor() {
+        let mut shaken = Stillness::new(20, 0.01, 0.5);
+        let mut turned = Stillness::new(20, 0.01, 0.5);
+        let mut noise = Noise::new(2);
+        for _ in 0..40 {
+            let quiet = [0.0, 0.0, 9.81];
+            shaken.push(&[2.0 * noise.next() as f32, 0.0, 9.81], &[0.0; 3]);
+            turned.push(&quiet, &[0.0, 3.0 * noise.next() as f32, 0.0]);
+        }
+        assert!(!shaken.is_still());
+        assert!(!turned.is_still());
+    }
+
+    /// Gyro readings at rest around `bias`
+    fn resting(bias: &Vector3<f64>, noise: &mut Noise) -> [f32; 3] {
+        (bias + noise.vector() * 0.05).cast::<f32>().into()
+    }
+
+    fn moments_of(readings: &[[f32; 3]]) -> (Vector3<f64>, Vector3<f64>) {
+        let readings: Vec<Vector3<f64>> = readings
+            .iter()
+            .map(|r| Vector3::from(*r).cast::<f64>())
+            .collect();
+        moments(readings.iter())
+    }
+
+    #[test]
+    fn gyro_bias_and_noise_density_of_still_windows() {
+        let bias = Vector3::new(0.2, -0.1, 0.05);
+        let mut estimator = GyroBiasEstimator::new(Stillness::new(20, 0.01, 0.5));
+        assert_eq!(estimator.estimate(), None);
+        let mut noise = Noise::new(3);
+        let readings: Vec<[f32; 3]> = (0..100).map(|_| resting(&bias, &mut noise)).collect();
+        for (i, gyro) in readings.iter().enumerate() {
+            assert_eq!(estimator.push(&[0.0, 0.0, 9.81], gyro, 0.01), i >= 19);
+        }
+        // Every reading counted once although each is in up to 20 still windows
+        assert_eq!(estimator.count(), 100);
+        let (mean, variance) = moments_of(&readings);
+        let gyro = estimator.estimate().unwrap();
+        assert!((gyro.bias - mean).norm() < 1e-9, "{}", gyro.bias);
+        assert!((gyro.bias - bias).norm() < 0.01);
+        let density = variance.map(|v| (v * 0.01).sqrt());
+        assert!((gyro.noise_density - density).norm() < 1e-9);
+
+        estimator.clear();
+        assert_eq!(estimator.count(), 0);
+        assert!(!estimator.is_still());
+    }
+
+    #[test]
+    fn gyro_bias_skips_windows_with_motion() {
+        let bias = Vector3::new(0.2, -0.1, 0.05);
+        let mut estimator = GyroBiasEstimator::new(Stillness::new(20, 0.01, 0.5));
+        let mut still = vec![];
+        let mut noise = Noise::new(4);
+        for i in 0..120 {
+            let gyro = if (40..80).contains(&i) {
+                [3.0 * noise.next() as f32 + 5.0, 0.0, 0.0]
+            } else {
+                let gyro = resting(&bias, &mut noise);
+                still.push(gyro);
+                gyro
+            };
+            estimator.push(&[0.0, 0.0, 9.81], &gyro, 0.01);
+        }
+        assert_eq!(estimator.count(), 80);
+        let (mean, _) = moments_of(&still);
+        let gyro = estimator.estimate().unwrap();
+        assert!((gyro.bias - mean).norm() < 1e-9, "{}", gyro.bias);
+    }
+}
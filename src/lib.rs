@@ -0,0 +1,231 @@
+//! Magnetometer calibration
+//!
+//! Ellipsoid fitting, the geomagnetic field model and the sample format of the
+//! [test firmware](https://github.com/copterust/proving-ground/tree/master/ahrs-ekf).
+//! With the `bevy` feature [`CalibrationPlugin`] keeps incoming samples and the
+//! current calibration as resources.
+
+use nalgebra::{Matrix3, Vector3};
+use serde::{Deserialize, Serialize};
+
+pub mod binary;
+pub mod command;
+pub mod coverage;
+pub mod document;
+pub mod export;
+#[cfg(test)]
+mod fixture;
+pub mod framing;
+pub mod geomag;
+pub mod imu;
+pub mod math;
+#[cfg(feature = "bevy")]
+mod plugin;
+pub mod refine;
+
+#[cfg(feature = "bevy")]
+pub use plugin::{CalibrationPlugin, SampleRead, Samples};
+
+/// Magnetometer reports milligauss, the field model nanotesla
+pub const NT_PER_UNIT: f64 = 100.0;
+
+/// One line of the test firmware output
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Sample {
+    pub dt: f32,
+    pub accel: [f32; 3],
+    pub gyro: [f32; 3],
+    pub cal_mag: [f32; 3],
+    pub state: [[f32; 7]; 1],
+    pub raw_mag: [f32; 3],
+    /// Incremented by the firmware for every sample, to detect lost ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u32>,
+}
+
+/// Soft iron matrix `a_1` and hard iron offset `b`, calibrated is `a_1 * (raw - b)`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bevy", derive(bevy::prelude::Resource))]
+pub struct Calibration {
+    pub a_1: Matrix3<f64>,
+    pub b: Vector3<f64>,
+    /// Quality of the fit this calibration came from
+    pub report: Option<math::FitReport>,
+    /// Six-position accelerometer calibration, if one was made
+    pub accel: Option<imu::AccelCalibration>,
+    /// Gyroscope bias estimated at rest, if one was taken
+    pub gyro: Option<imu::GyroCalibration>,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            a_1: Matrix3::identity(),
+            b: Vector3::zeros(),
+            report: None,
+            accel: None,
+            gyro: None,
+        }
+    }
+}
+
+/// Model and optional stages of [`Calibration::fit_with`]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FitOptions {
+    /// Fixed model, picked by [`math::choose_model`] if `None`
+    pub model: Option<math::FitModel>,
+    /// Reject outliers with [`math::robust_ellipsoid_fit`]
+    pub robust: Option<math::RobustOptions>,
+    /// Refine the result on the kept samples with [`refine::refine`]
+    pub refine: Option<refine::RefineOptions>,
+}
+
+/// Automatically chosen models with a larger RMS residual relative to the field fall back
+const MAX_AUTO_RELATIVE_RMS: f64 = 0.1;
+
+/// Samples not listed in `rejected`, which is sorted
+fn kept(samples: &[[f64; 3]], rejected: &[usize]) -> Vec<[f64; 3]> {
+    samples
+        .iter()
+        .enumerate()
+        .filter(|(j, _)| rejected.binary_search(j).is_err())
+        .map(|(_, s)| *s)
+        .collect()
+}
+
+impl Calibration {
+    /// Fits raw magnetometer samples to a sphere of radius `field`
+    pub fn fit(samples: &[[f64; 3]], field: f64) -> Result<Self, math::FitError> {
+        Calibration::fit_model(samples, field, math::FitModel::Full)
+    }
+
+    /// Like [`Calibration::fit`] with only the parameters of `model`
+    pub fn fit_model(
+        samples: &[[f64; 3]],
+        field: f64,
+        model: math::FitModel,
+    ) -> Result<Self, math::FitError> {
+        let (m, n, d) = model.fit(samples, &vec![1.0; samples.len()])?;
+        let (a_1, b) = math::ellipsoid_to_calibration(m, n, d, field)?;
+        let mut report = math::FitReport::new(samples, &a_1, &b, field);
+        report.model = Some(model);
+        Ok(Calibration {
+            a_1,
+            b,
+            report: Some(report),
+            accel: None,
+            gyro: None,
+        })
+    }
+
+    /// Like [`Calibration::fit_model`] but ignores outliers, returns indices of rejected samples
+    ///
+    /// The report only covers the samples that were kept.
+    pub fn fit_robust(
+        samples: &[[f64; 3]],
+        field: f64,
+        model: math::FitModel,
+        options: &math::RobustOptions,
+    ) -> Result<(Self, Vec<usize>), math::FitError> {
+        let fit = math::robust_ellipsoid_fit(samples, model, options)?;
+        let (a_1, b) = math::ellipsoid_to_calibration(fit.m, fit.n, fit.d, field)?;
+        let mut report = math::FitReport::new(&kept(samples, &fit.rejected), &a_1, &b, field);
+        report.model = Some(model);
+        let calibration = Calibration {
+            a_1,
+            b,
+            report: Some(report),
+            accel: None,
+            gyro: None,
+        };
+        Ok((calibration, fit.rejected))
+    }
+
+    /// Fit with the model and stages of `options`, returns indices of rejected samples
+    ///
+    /// If the model was chosen automatically and cannot be fitted or fits badly, simpler
+    /// ones are tried. Samples in a plane are not refined, that would only move `b` along
+    /// the direction the samples cannot determine.
+    pub fn fit_with(
+        samples: &[[f64; 3]],
+        field: f64,
+        options: &FitOptions,
+    ) -> Result<(Self, Vec<usize>), math::FitError> {
+        let mut model = options.model.unwrap_or_else(|| math::choose_model(samples));
+        let (calibration, rejected) = loop {
+            let fitted = match &options.robust {
+                Some(robust) => Calibration::fit_robust(samples, field, model, robust),
+                None => Calibration::fit_model(samples, field, model).map(|c| (c, vec![])),
+            };
+            let implausible = |(c, _): &(Calibration, Vec<usize>)| {
+                c.report
+                    .is_some_and(|r| r.rms > MAX_AUTO_RELATIVE_RMS * field)
+            };
+            match (fitted, model.simpler()) {
+                (Ok(fitted), Some(simpler)) if options.model.is_none() && implausible(&fitted) => {
+                    model = simpler
+                }
+                (Ok(fitted), _) => break fitted,
+                (Err(_), Some(simpler)) if options.model.is_none() => model = simpler,
+                (Err(e), _) => return Err(e),
+            }
+        };
+        let kept = kept(samples, &rejected);
+        let Some(refine) = options
+            .refine
+            .filter(|_| math::undetermined_direction(&kept).is_none())
+        else {
+            return Ok((calibration, rejected));
+        };
+        // Do not bring back parameters the samples could not determine
+        let mut refine = refine;
+        if model != math::FitModel::Full {
+            refine.soft_iron = refine::SoftIron::for_model(model);
+        }
+        let mut refined = calibration.refine(&kept, field, &refine)?;
+        if let Some(report) = refined.report.as_mut() {
+            report.model = Some(model);
+        }
+        Ok((refined, rejected))
+    }
+
+    /// Minimises the geometric error on `samples` starting from this calibration
+    pub fn refine(
+        &self,
+        samples: &[[f64; 3]],
+        field: f64,
+        options: &refine::RefineOptions,
+    ) -> Result<Self, math::FitError> {
+        let (a_1, b, refinement) = refine::refine(samples, &self.a_1, &self.b, field, options)?;
+        let mut report = math::FitReport::new(samples, &a_1, &b, field);
+        report.refinement = Some(refinement);
+        Ok(Calibration {
+            a_1,
+            b,
+            report: Some(report),
+            accel: self.accel,
+            gyro: self.gyro,
+        })
+    }
+
+    /// Applies the calibration to a raw magnetometer reading
+    pub fn apply(&self, raw: &[f32; 3]) -> [f32; 3] {
+        math::calibrated_sample(raw, &self.a_1.cast(), &self.b.cast()).into()
+    }
+
+    /// Applies the accelerometer calibration, if any, to a raw accelerometer reading
+    pub fn apply_accel(&self, raw: &[f32; 3]) -> [f32; 3] {
+        match &self.accel {
+            Some(accel) => accel.apply(raw),
+            None => *raw,
+        }
+    }
+
+    /// Subtracts the gyroscope bias, if any, from a raw gyroscope reading
+    pub fn apply_gyro(&self, raw: &[f32; 3]) -> [f32; 3] {
+        match &self.gyro {
+            Some(gyro) => gyro.apply(raw),
+            None => *raw,
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Fits while samples are being collected
+//!
+//! Every sample is added to a [`Scatter`], so refitting costs the same however many
+//! samples were collected. The fit report still goes over all samples. The point cloud is
+//! redrawn whenever the calibration or view changes.
+use bevy::prelude::*;
+use bevy::render::mesh::VertexAttributeValues;
+
+use bevy_mag::coverage::Coverage;
+use bevy_mag::math::{self, FitError, FitModel, Scatter};
+use bevy_mag::{Calibration, SampleRead, Samples};
+
+use crate::view::View;
+use crate::{AppState, Field, RawMeasurements, SampleKind};
+
+#[derive(Resource)]
+pub struct LiveFit {
+    pub enabled: bool,
+    /// Refit after this many new samples
+    pub every: usize,
+    scatter: Scatter,
+    pending: usize,
+    pub error: Option<FitError>,
+}
+
+impl Default for LiveFit {
+    fn default() -> Self {
+        LiveFit {
+            enabled: false,
+            every: 50,
+            scatter: Scatter::default(),
+            pending: 0,
+            error: None,
+        }
+    }
+}
+
+pub struct LiveFitPlugin;
+
+impl Plugin for LiveFitPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LiveFit>()
+            .add_system(live_fit)
+            .add_system(recalibrate_cloud);
+    }
+}
+
+fn live_fit(
+    mut live: ResMut<LiveFit>,
+    mut ev_samples: EventReader<SampleRead>,
+    mut calibration: ResMut<Calibration>,
+    mut coverage: ResMut<Coverage>,
+    state: Res<AppState>,
+    field: Res<Field>,
+    history: Res<Samples>,
+) {
+    // A new collection starts from an empty scatter
+    if state.is_changed() && *state == AppState::Collect {
+        live.scatter = Scatter::default();
+        live.pending = 0;
+    }
+    for SampleRead { sample, .. } in ev_samples.iter() {
+        live.scatter.add(&sample.raw_mag.map(|c| c as f64));
+        live.pending += 1;
+    }
+    if !live.enabled || *state != AppState::Collect || live.pending < live.every {
+        return;
+    }
+    live.pending = 0;
+    let field = field.f as f64;
+    let fitted = live
+        .scatter
+        .fit()
+        .and_then(|(m, n, d)| math::ellipsoid_to_calibration(m, n, d, field));
+    match fitted {
+        Ok((a_1, b)) => {
+            let samples: Vec<[f64; 3]> = history
+                .all
+                .iter()
+                .map(|s| s.raw_mag.map(|c| c as f64))
+                .collect();
+            let mut report = math::FitReport::new(&samples, &a_1, &b, field);
+            report.model = Some(FitModel::Full);
+            coverage.set_offset(Some(b));
+            *calibration = Calibration {
+                a_1,
+                b,
+                report: Some(report),
+                accel: calibration.accel,
+                gyro: calibration.gyro,
+            };
+            live.error = None;
+        }
+        Err(e) => live.error = Some(e),
+    }
+}
+
+/// Moves collected points to where the current calibration and view put them
+fn recalibrate_cloud(
+    calibration: Res<Calibration>,
+    kind: Res<SampleKind>,
+    view: Res<View>,
+    history: Res<Samples>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    query: Query<&Handle<Mesh>, With<RawMeasurements>>,
+) {
+    if !(calibration.is_changed() || view.is_changed() || kind.is_changed()) {
+        return;
+    }
+    for handle in &query {
+        let Some(mesh) = meshes.get_mut(handle) else {
+            continue;
+        };
+        if let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION)
+        {
+            for (position, sample) in positions.iter_mut().zip(&history.all) {
+                *position = view.position(sample, &calibration, *kind);
+            }
+        }
+    }
+}
@@ -19,50 +19,103 @@ use bevy::{
     },
 };
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
-use bevy_serial::{SerialPlugin, SerialReadEvent};
-use nalgebra::{Matrix3, Vector3};
-use rand::distributions::{Distribution, Uniform};
-use serde::Deserialize;
-use serde_json;
-
-mod math;
-
-// Get yours at https://www.ngdc.noaa.gov/geomag/calculators/magcalc.shtml#igrfwmm
-const F: f32 = 486.027;
+use bevy_mag::{
+    coverage::Coverage, document, export, export::ExportFormat, geomag, math,
+    refine::RefineOptions, Calibration, CalibrationPlugin, FitOptions, Sample, SampleRead, Samples,
+    NT_PER_UNIT,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use framing_ui::FrameRead;
+use std::path::PathBuf;
+
+mod accel_wizard;
+mod calibrate;
+mod coverage_ui;
+mod diagnostics;
+mod framing_ui;
+mod gyro_bias;
+mod live;
+mod plots;
+mod recorder;
+mod replay;
+mod serial;
+mod upload;
+mod view;
+
+/// Where and when the calibration takes place
+#[derive(Resource, Debug, Clone, PartialEq)]
+struct Location {
+    /// Derive [`Field`] from the model instead of entering it by hand
+    use_model: bool,
+    /// Geodetic latitude, degrees
+    latitude: f64,
+    /// Longitude, degrees east
+    longitude: f64,
+    /// Altitude above WGS-84 ellipsoid, km
+    altitude: f64,
+    /// Decimal year
+    year: f64,
+}
 
-#[derive(Resource, Debug, Deref, DerefMut)]
-struct WrappedMarg(MargEkf);
+impl Default for Location {
+    fn default() -> Self {
+        Location {
+            use_model: false,
+            latitude: 0.0,
+            longitude: 0.0,
+            altitude: 0.0,
+            year: geomag::current_year(),
+        }
+    }
+}
 
-#[derive(Resource, Debug)]
-struct Calibration {
-    a_1: Matrix3<f64>,
-    b: Vector3<f64>,
-    marg: WrappedMarg,
+#[derive(Resource, Deref)]
+struct FieldModel(geomag::MagneticModel);
+
+/// Expected geomagnetic field at the current location
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+struct Field {
+    /// Total intensity in sensor units
+    f: f32,
+    /// Degrees, positive down
+    inclination: f32,
+    /// Degrees, positive east
+    declination: f32,
 }
 
-#[derive(Resource, Default)]
-struct Samples {
-    all: Vec<Sample>,
+impl Default for Field {
+    // Get yours at https://www.ngdc.noaa.gov/geomag/calculators/magcalc.shtml#igrfwmm
+    fn default() -> Self {
+        Field {
+            f: 486.027,
+            inclination: 66.8579,
+            declination: 5.9791,
+        }
+    }
 }
 
-#[derive(Default, Deserialize)]
-pub struct Sample {
-    pub dt: f32,
-    pub accel: [f32; 3],
-    pub gyro: [f32; 3],
-    pub cal_mag: [f32; 3],
-    pub state: [[f32; 7]; 1],
-    pub raw_mag: [f32; 3],
+impl Field {
+    fn at(model: &geomag::MagneticModel, location: &Location) -> Self {
+        let field = model.field(
+            location.latitude,
+            location.longitude,
+            location.altitude,
+            location.year,
+        );
+        Field {
+            f: (field.f / NT_PER_UNIT) as f32,
+            inclination: field.inclination as f32,
+            declination: field.declination as f32,
+        }
+    }
 }
 
-impl Default for Calibration {
+#[derive(Resource, Debug, Deref, DerefMut)]
+struct WrappedMarg(MargEkf);
+
+impl Default for WrappedMarg {
     fn default() -> Self {
-        let marg = WrappedMarg(ahrs::MargEkf::new());
-        Calibration {
-            a_1: Matrix3::identity(),
-            b: Vector3::zeros(),
-            marg,
-        }
+        WrappedMarg(ahrs::MargEkf::new())
     }
 }
 
@@ -72,12 +125,69 @@ enum AppState {
     Calibrate,
 }
 
-#[derive(Resource, PartialEq)]
+/// Which magnetometer readings to show
+#[derive(Resource, PartialEq, Clone, Copy, ValueEnum)]
 enum SampleKind {
+    /// Read raw samples and calibrate
     Raw,
+    /// Samples are scaled at the device
     Cal,
 }
 
+impl SampleKind {
+    /// Calibrated magnetometer reading of `sample`
+    fn calibrated(&self, sample: &Sample, calibration: &Calibration) -> [f32; 3] {
+        match self {
+            SampleKind::Raw => calibration.apply(&sample.raw_mag),
+            SampleKind::Cal => sample.cal_mag,
+        }
+    }
+}
+
+/// Indices of samples the last fit rejected, in order of arrival
+#[derive(Resource, Default)]
+struct Outliers(Vec<usize>);
+
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Serial port or recorded session to replay, pick a port in the Connection window if not given
+    #[arg(long, short)]
+    port: Option<String>,
+    #[arg(long, value_enum, default_value_t = Source::Auto)]
+    source: Source,
+    #[command(flatten)]
+    serial: serial::SerialSettings,
+    /// Encoding of the samples
+    #[arg(long, value_enum, default_value_t = framing_ui::Protocol::Auto)]
+    protocol: framing_ui::Protocol,
+    #[arg(long, value_enum, default_value_t = SampleKind::Raw)]
+    mode: SampleKind,
+    /// Calibration file to apply from the start, `.json` or `.toml`
+    #[arg(long)]
+    calibration: Option<PathBuf>,
+}
+
+/// Where samples come from
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Source {
+    /// Replay if `--port` is a regular file, serial port otherwise
+    Auto,
+    Serial,
+    /// Recorded session
+    Replay,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Fit a recorded session without opening a window
+    Calibrate(calibrate::CalibrateArgs),
+    /// Render a saved calibration for firmware
+    Export(calibrate::ExportArgs),
+}
+
 impl Default for AppState {
     fn default() -> Self {
         AppState::Collect
@@ -85,78 +195,327 @@ impl Default for AppState {
 }
 
 fn main() {
-    let name = std::env::args().skip(1).next();
-    let name = name.as_deref().unwrap_or("tilt1.txt");
-
-    let kind = std::env::args().skip(2).next().unwrap_or("raw".to_string());
-    let kind = if kind == "cal" {
-        SampleKind::Cal
-    } else {
-        SampleKind::Raw
+    let cli = Cli::parse();
+    if let Some(command) = cli.command {
+        let result = match command {
+            Command::Calibrate(args) => calibrate::run(args),
+            Command::Export(args) => calibrate::run_export(args),
+        };
+        if let Err(e) = result {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let port = cli.port.as_deref();
+    // Serial ports are character devices, regular files are recorded sessions
+    let replaying = match cli.source {
+        Source::Auto => port.is_some_and(|p| std::path::Path::new(p).is_file()),
+        Source::Serial => false,
+        Source::Replay => true,
+    };
+    let kind = cli.mode;
+    let calibration = match &cli.calibration {
+        Some(path) => match document::load(path) {
+            Ok(calibration) => calibration,
+            Err(e) => {
+                eprintln!("error: {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => Calibration::default(),
     };
 
-    App::new()
-        .add_plugins(DefaultPlugins)
-        .add_plugin(EguiPlugin)
-        .add_plugin(SerialPlugin::new(name, 460800))
+    let mut app = App::new();
+    app.add_plugins(DefaultPlugins).add_plugin(EguiPlugin);
+    match (replaying, port) {
+        (true, Some(path)) => {
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(e) => {
+                    eprintln!("error: {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            };
+            app.add_plugin(replay::ReplayPlugin::new(path, text));
+        }
+        (true, None) => {
+            eprintln!("error: --source replay needs the recorded session as --port");
+            std::process::exit(1);
+        }
+        (false, port) => {
+            app.add_plugin(serial::SerialConnectionPlugin::new(port, cli.serial))
+                .add_plugin(upload::UploadPlugin);
+        }
+    }
+    app.add_plugin(CalibrationPlugin)
+        .add_plugin(coverage_ui::CoveragePlugin)
+        .add_plugin(live::LiveFitPlugin)
+        .add_plugin(view::ViewPlugin)
+        .add_plugin(framing_ui::FramingPlugin::new(cli.protocol))
+        .add_plugin(diagnostics::DiagnosticsPlugin)
+        .add_plugin(plots::PlotsPlugin)
+        .add_plugin(accel_wizard::AccelWizardPlugin)
+        .add_plugin(gyro_bias::GyroBiasPlugin)
+        .add_plugin(recorder::RecorderPlugin::new(!replaying))
         .add_plugin(MaterialPlugin::<ParticlesMaterial>::default())
         .add_plugin(MaterialPlugin::<LineMaterial>::default())
         .insert_resource(ClearColor(Color::hex("0f0f0f").unwrap()))
         .insert_resource(AppState::Collect)
-        .insert_resource(Calibration::default())
+        .insert_resource(calibration)
+        .insert_resource(WrappedMarg::default())
         .insert_resource(kind)
-        .insert_resource(Samples::default())
+        .insert_resource(FieldModel(geomag::wmm()))
+        .insert_resource(Location::default())
+        .insert_resource(Field::default())
+        .init_resource::<Outliers>()
         .add_system(update_time_for_particles_material)
         .add_system(read_serial)
         .add_system(pan_orbit_camera)
         .add_system(draw_ui)
-        .add_startup_system(setup)
-        .run();
+        .add_system(draw_location_ui)
+        .add_system(update_field_markers)
+        .add_startup_system(setup);
+    app.run();
 }
 
 fn draw_ui(
     mut contexts: EguiContexts,
     mut state: ResMut<AppState>,
-    mut query: Query<&Handle<Mesh>, With<RawMeasurements>>,
-    mut meshes: ResMut<Assets<Mesh>>,
     mut calibration: ResMut<Calibration>,
     history: Res<Samples>,
+    field: Res<Field>,
+    coverage: Res<Coverage>,
+    mut auto_done: ResMut<coverage_ui::AutoDone>,
+    mut outliers: ResMut<Outliers>,
+    mut live: ResMut<live::LiveFit>,
+    mut fit_options: Local<FitOptions>,
+    mut fit_error: Local<Option<math::FitError>>,
+    mut files: Local<FileControls>,
 ) {
     egui::Window::new("Calibration").show(contexts.ctx_mut(), |ui| {
         if AppState::Collect == *state {
-            if ui.button("At Rest").clicked() {
-                let accel = history.all.iter().map(|s| s.accel);
-                let a_n = accel.len() as f32;
-                let accel_mean = accel
-                    .fold([0., 0., 0.], |s, a| [s[0] + a[0], s[1] + a[1], s[2] + a[2]])
-                    .map(|c| c / a_n);
-                let gyro = history.all.iter().map(|s| s.gyro);
-                let gyro_mean = gyro
-                    .fold([0., 0., 0.], |s, a| [s[0] + a[0], s[1] + a[1], s[2] + a[2]])
-                    .map(|c| c / a_n);
-                println!(
-                    "Mean accel at rest: {:?}, mean gyro at rest {:?}",
-                    accel_mean, gyro_mean
+            egui::ComboBox::from_label("Model")
+                .selected_text(fit_options.model.map_or("auto", |m| m.name()))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut fit_options.model, None, "auto");
+                    for model in math::FitModel::ALL {
+                        ui.selectable_value(&mut fit_options.model, Some(model), model.name());
+                    }
+                });
+            let mut robust = fit_options.robust.is_some();
+            ui.checkbox(&mut robust, "Reject outliers");
+            fit_options.robust = robust.then(math::RobustOptions::default);
+            let mut refine = fit_options.refine.is_some();
+            ui.checkbox(&mut refine, "Refine (Levenberg-Marquardt)");
+            fit_options.refine = refine.then(RefineOptions::default);
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut live.enabled, "Live fit every");
+                ui.add(egui::DragValue::new(&mut live.every).clamp_range(10..=1000));
+                ui.label("samples");
+            });
+            if let Some(e) = live.error.as_ref().filter(|_| live.enabled) {
+                ui.label(format!("Live fit: {}", e));
+            }
+            if ui.button("Done").clicked() || auto_done.ready(&coverage, history.all.len()) {
+                // Fit raw readings, displayed points may already be calibrated
+                let samples: Vec<[f64; 3]> = history
+                    .all
+                    .iter()
+                    .map(|s| s.raw_mag.map(|c| c as f64))
+                    .collect();
+                let fitted = Calibration::fit_with(&samples, field.f as f64, &fit_options);
+                match fitted {
+                    Ok((fitted, rejected)) => {
+                        *state = AppState::Calibrate;
+                        *fit_error = None;
+                        // Fits only cover the magnetometer
+                        *calibration = Calibration {
+                            accel: calibration.accel,
+                            gyro: calibration.gyro,
+                            ..fitted
+                        };
+                        outliers.0 = rejected;
+                    }
+                    Err(e) => *fit_error = Some(e),
+                }
+            }
+
+            if let Some(e) = fit_error.as_ref() {
+                ui.colored_label(
+                    egui::Color32::RED,
+                    format!("Calibration failed: {}. Keep collecting.", e),
                 );
             }
+        }
+
+        if let Some(report) = &calibration.report {
+            ui.separator();
+            ui.monospace(report.to_string());
+            if !outliers.0.is_empty() {
+                ui.label(format!("{} samples rejected as outliers", outliers.0.len()));
+            }
+        }
+
+        ui.separator();
+        if let Some(loaded) = files.draw(ui, &calibration) {
+            *calibration = loaded;
+        }
+    });
+}
+
+/// Save, load and export of the current calibration
+struct FileControls {
+    path: String,
+    format: ExportFormat,
+    status: Option<Result<String, String>>,
+}
 
-            if ui.button("Done").clicked() {
-                *state = AppState::Calibrate;
-                let handle = query.get_single_mut().expect("Raw Measurements mesh to be");
-                let mesh = meshes.get_mut(handle).expect("getting mesh");
-                let attribute_positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION);
-                if let Some(VertexAttributeValues::Float32x3(positions)) = attribute_positions {
-                    let samples: Vec<[f64; 3]> = positions
-                        .into_iter()
-                        .map(|arr| [arr[0] as f64, arr[1] as f64, arr[2] as f64])
-                        .collect();
-                    let (m, n, d) = math::ellipsoid_fit(&(samples as Vec<[f64; 3]>));
-                    let (a_1, b) = math::ellipsoid_to_calibration(m, n, d, F as f64);
-                    calibration.a_1 = a_1;
-                    calibration.b = b;
-                    println!("Calibration done: {:?}", calibration);
+impl Default for FileControls {
+    fn default() -> Self {
+        FileControls {
+            path: "calibration.json".to_string(),
+            format: ExportFormat::CHeader,
+            status: None,
+        }
+    }
+}
+
+impl FileControls {
+    /// Returns a calibration that was loaded
+    fn draw(&mut self, ui: &mut egui::Ui, calibration: &Calibration) -> Option<Calibration> {
+        let mut loaded = None;
+        ui.text_edit_singleline(&mut self.path);
+        let file = PathBuf::from(&self.path);
+        ui.horizontal(|ui| {
+            if ui.button("Save").clicked() {
+                self.status = Some(
+                    document::save(calibration, &file)
+                        .map(|_| format!("Saved {}", file.display()))
+                        .map_err(|e| e.to_string()),
+                );
+            }
+            if ui.button("Load").clicked() {
+                self.status = Some(match document::load(&file) {
+                    Ok(calibration) => {
+                        loaded = Some(calibration);
+                        Ok(format!("Loaded {}", file.display()))
+                    }
+                    Err(e) => Err(e.to_string()),
+                });
+            }
+        });
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source("export format")
+                .selected_text(self.format.name())
+                .show_ui(ui, |ui| {
+                    for format in ExportFormat::ALL {
+                        ui.selectable_value(&mut self.format, format, format.name());
+                    }
+                });
+            if ui.button("Export").clicked() {
+                let target = file.with_extension(self.format.extension());
+                self.status = Some(
+                    export::render(calibration, self.format)
+                        .map_err(|e| e.to_string())
+                        .and_then(|text| {
+                            std::fs::write(&target, text)
+                                .map(|_| format!("Exported {}", target.display()))
+                                .map_err(|e| format!("{}: {}", target.display(), e))
+                        }),
+                );
+            }
+        });
+        match &self.status {
+            Some(Ok(status)) => {
+                ui.label(status);
+            }
+            Some(Err(e)) => {
+                ui.colored_label(egui::Color32::RED, e);
+            }
+            None => {}
+        }
+        loaded
+    }
+}
+
+fn draw_location_ui(
+    mut contexts: EguiContexts,
+    model: Res<FieldModel>,
+    mut location: ResMut<Location>,
+    mut field: ResMut<Field>,
+) {
+    egui::Window::new("Location").show(contexts.ctx_mut(), |ui| {
+        let mut edited = location.clone();
+        ui.checkbox(&mut edited.use_model, "Use field model");
+        egui::Grid::new("location").show(ui, |ui| {
+            if edited.use_model {
+                ui.label("Latitude, °");
+                ui.add(
+                    egui::DragValue::new(&mut edited.latitude)
+                        .clamp_range(-90.0..=90.0)
+                        .speed(0.1),
+                );
+                ui.end_row();
+                ui.label("Longitude, °");
+                ui.add(
+                    egui::DragValue::new(&mut edited.longitude)
+                        .clamp_range(-180.0..=180.0)
+                        .speed(0.1),
+                );
+                ui.end_row();
+                ui.label("Altitude, km");
+                ui.add(
+                    egui::DragValue::new(&mut edited.altitude)
+                        .clamp_range(-1.0..=600.0)
+                        .speed(0.01),
+                );
+                ui.end_row();
+                ui.label("Year");
+                ui.add(egui::DragValue::new(&mut edited.year).speed(0.01));
+                ui.end_row();
+            } else {
+                // Field is entered by hand, only touch the resource on edits
+                let mut manual = *field;
+                ui.label("F");
+                ui.add(egui::DragValue::new(&mut manual.f).speed(0.1));
+                ui.end_row();
+                ui.label("Inclination, °");
+                ui.add(
+                    egui::DragValue::new(&mut manual.inclination)
+                        .clamp_range(-90.0..=90.0)
+                        .speed(0.01),
+                );
+                ui.end_row();
+                ui.label("Declination, °");
+                ui.add(
+                    egui::DragValue::new(&mut manual.declination)
+                        .clamp_range(-180.0..=180.0)
+                        .speed(0.01),
+                );
+                ui.end_row();
+                if manual != *field {
+                    *field = manual;
                 }
             }
+        });
+        if edited != *location {
+            if edited.use_model {
+                *field = Field::at(&model, &edited);
+            }
+            *location = edited;
+        }
+        if location.use_model {
+            ui.label(format!(
+                "F = {:.3}, I = {:.2}°, D = {:.2}°",
+                field.f, field.inclination, field.declination
+            ));
+            if !model.is_valid(location.year) {
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    format!("{} is not valid for {:.1}", model.name, location.year),
+                );
+            }
         }
     });
 }
@@ -164,14 +523,51 @@ fn draw_ui(
 #[derive(Component)]
 struct RawMeasurements;
 
+#[derive(Component)]
+struct North;
+
+/// Returns the line pointing along the expected field vector
+fn north_line(field: &Field) -> LineList {
+    let i = field.inclination.to_radians();
+    let d = field.declination.to_radians();
+    let x: f32 = 1.2 * field.f * i.cos() * d.cos();
+    let y: f32 = 1.2 * field.f * i.cos() * d.sin();
+    let z: f32 = 1.2 * field.f * i.sin();
+    LineList {
+        lines: vec![(Vec3::ZERO, Vec3::new(x, y, z))],
+    }
+}
+
+/// Keeps the north line in sync with the field model
+fn update_field_markers(
+    field: Res<Field>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    north: Query<&Handle<Mesh>, With<North>>,
+) {
+    if !field.is_changed() {
+        return;
+    }
+    for handle in &north {
+        if let Some(mesh) = meshes.get_mut(handle) {
+            *mesh = Mesh::from(north_line(&field));
+        }
+    }
+}
+
 fn read_serial(
     mut meshes: ResMut<Assets<Mesh>>,
     mut query: Query<&Handle<Mesh>, With<RawMeasurements>>,
-    mut ev_serial: EventReader<SerialReadEvent>,
+    mut ev_frames: EventReader<FrameRead>,
+    mut diagnostics: ResMut<diagnostics::Diagnostics>,
+    mut ev_samples: EventWriter<SampleRead>,
     state: Res<AppState>,
-    mut calibration: ResMut<Calibration>,
+    calibration: Res<Calibration>,
+    mut marg: ResMut<WrappedMarg>,
     kind: Res<SampleKind>,
-    mut history: ResMut<Samples>,
+    view: Res<view::View>,
+    field: Res<Field>,
+    outliers: Res<Outliers>,
+    mut coverage: ResMut<Coverage>,
     mut cubes: Query<(&mut Transform, &QuatTarget)>,
 ) {
     let handle = query.get_single_mut().expect("Raw Measurements mesh to be");
@@ -196,18 +592,33 @@ fn read_serial(
 
     let mut bubu = Box::new(Sample::default());
 
-    for SerialReadEvent(_label, buffer) in ev_serial.iter() {
-        let s = match String::from_utf8(buffer.clone()) {
-            Ok(x) => x,
-            Err(_) => continue,
-        };
-
-        *bubu = match serde_json::from_str(&s) {
-            Ok(k) => k,
-            Err(_) => continue,
+    for frame in ev_frames.iter() {
+        let line = match frame {
+            FrameRead::Line(line) => {
+                *bubu = match serde_json::from_str(line) {
+                    Ok(k) => k,
+                    // Replies to commands start with `$`
+                    Err(_) if line.starts_with('$') => continue,
+                    Err(e) => {
+                        diagnostics.rejected(line, e);
+                        continue;
+                    }
+                };
+                line.clone()
+            }
+            FrameRead::Sample(sample) => {
+                *bubu = (**sample).clone();
+                // Recorded as JSON, so sessions replay the same way
+                serde_json::to_string(sample).expect("samples to serialize")
+            }
         };
-        let quat = calibration.marg.0.state.clone();
-        let mut g = bubu.gyro;
+        diagnostics.accepted(&bubu);
+        ev_samples.send(SampleRead {
+            line,
+            sample: (*bubu).clone(),
+        });
+        let quat = marg.0.state.clone();
+        let mut g = calibration.apply_gyro(&bubu.gyro);
         g = g
             .iter()
             .map(|e| e * 2. * std::f32::consts::PI / 180.)
@@ -215,23 +626,15 @@ fn read_serial(
             .try_into()
             .expect("to convert g vector to array");
 
-        calibration.marg.0.predict(g[0], g[1], g[2], bubu.dt);
-        let cal = match *kind {
-            SampleKind::Raw => math::calibrated_sample(
-                &bubu.raw_mag,
-                &calibration.a_1.map(|x| x as f32),
-                &calibration.b.map(|x| x as f32),
-            )
-            .into(),
-            SampleKind::Cal => bubu.cal_mag,
-        };
-        let a = bubu.accel;
+        marg.0.predict(g[0], g[1], g[2], bubu.dt);
+        let cal = kind.calibrated(&bubu, &calibration);
+        let a = calibration.apply_accel(&bubu.accel);
         let a_norm = a.iter().map(|e| e.powi(2)).sum::<f32>().sqrt();
         let a = a.iter().map(|e| e / a_norm).collect::<Vec<f32>>();
         let m = cal;
         let m_norm = m.iter().map(|e| e.powi(2)).sum::<f32>().sqrt();
         let m = m.iter().map(|e| e / m_norm).collect::<Vec<f32>>();
-        calibration.marg.0.update(
+        marg.0.update(
             a.try_into().expect("wild success"),
             m.try_into().expect("same here"),
         );
@@ -240,15 +643,19 @@ fn read_serial(
             transform.rotation = Quat::from_xyzw(quat[1], quat[2], quat[3], quat[0]);
         }
 
-        history.all.push(*bubu);
-
-        positions.push([cal[0], cal[1], cal[2]]);
+        positions.push(view.position(&bubu, &calibration, *kind));
 
         if AppState::Collect == *state {
-            colors.push([1.0, 0.0, 0.0, 1.0]);
-        } else {
-            colors.push([0.0, 1.0, 0.0, 1.0])
+            coverage.add(&bubu.raw_mag);
         }
+        let palette = view::Palette {
+            view: &view,
+            calibration: &calibration,
+            field: field.f,
+            outliers: &outliers.0,
+            count: colors.len() + 1,
+        };
+        colors.push(palette.color(colors.len(), &bubu));
     }
 
     if positions.len() > 0 {
@@ -266,27 +673,12 @@ fn setup(
     mut materials: ResMut<Assets<ParticlesMaterial>>,
     mut cube_materials: ResMut<Assets<StandardMaterial>>,
     mut line_materials: ResMut<Assets<LineMaterial>>,
+    field: Res<Field>,
 ) {
-    let mut mesh = Mesh::new(PrimitiveTopology::PointList);
-    let uniform01 = Uniform::from(0.0..1.0);
-    let mut rng = rand::thread_rng();
-    let mut positions = vec![];
-    let mut colors = vec![];
-    for _ in 0..1000 {
-        let theta: f32 = 2.0 * std::f32::consts::PI * uniform01.sample(&mut rng);
-        let phi = (1.0 - 2.0 * uniform01.sample(&mut rng)).acos();
-        let x = F * phi.sin() * theta.cos();
-        let y = F * phi.sin() * theta.sin();
-        let z = F * phi.cos();
-        positions.push([x, y, z]);
-        colors.push([0.0, 0.0, 0.5, 1.0]);
-    }
-    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
-    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
-
+    let f = field.f;
     commands
         .spawn(PbrBundle {
-            mesh: meshes.add(Mesh::from(shape::Cube { size: F / 2. })),
+            mesh: meshes.add(Mesh::from(shape::Cube { size: f / 2. })),
             material: cube_materials.add(Color::rgb(1., 0.4, 0.2).into()),
             transform: Transform::from_xyz(0., 0.0, 0.0),
             ..default()
@@ -294,12 +686,6 @@ fn setup(
         .insert(QuatTarget);
 
     spawn_camera(&mut commands);
-    commands.spawn(MaterialMeshBundle {
-        mesh: meshes.add(mesh),
-        material: materials.add(ParticlesMaterial { time: 0.0 }),
-        transform: Transform::from_xyz(0.0, 0.0, 0.0),
-        ..default()
-    });
     // Uncalibrated Point cloud
     commands.spawn((
         MaterialMeshBundle {
@@ -312,7 +698,7 @@ fn setup(
     // Axis
     commands.spawn(MaterialMeshBundle {
         mesh: meshes.add(Mesh::from(LineList {
-            lines: vec![(Vec3::ZERO, Vec3::new(F / 2., 0.0, 0.0))],
+            lines: vec![(Vec3::ZERO, Vec3::new(f / 2., 0.0, 0.0))],
         })),
         transform: Transform::from_xyz(0.0, 0.0, 0.0),
         material: line_materials.add(LineMaterial { color: Color::RED }),
@@ -320,7 +706,7 @@ fn setup(
     });
     commands.spawn(MaterialMeshBundle {
         mesh: meshes.add(Mesh::from(LineList {
-            lines: vec![(Vec3::ZERO, Vec3::new(0.0, F / 2., 0.0))],
+            lines: vec![(Vec3::ZERO, Vec3::new(0.0, f / 2., 0.0))],
         })),
         transform: Transform::from_xyz(0.0, 0.0, 0.0),
         material: line_materials.add(LineMaterial {
@@ -330,28 +716,24 @@ fn setup(
     });
     commands.spawn(MaterialMeshBundle {
         mesh: meshes.add(Mesh::from(LineList {
-            lines: vec![(Vec3::ZERO, Vec3::new(0.0, 0.0, F / 2.))],
+            lines: vec![(Vec3::ZERO, Vec3::new(0.0, 0.0, f / 2.))],
         })),
         transform: Transform::from_xyz(0.0, 0.0, 0.0),
         material: line_materials.add(LineMaterial { color: Color::BLUE }),
         ..default()
     });
     // North
-    let i = 66.8579f32.to_radians();
-    let d = 5.9791f32.to_radians();
-    let x: f32 = 1.2 * F * i.cos() * d.cos();
-    let y: f32 = 1.2 * F * i.cos() * d.sin();
-    let z: f32 = 1.2 * F * i.sin();
-    commands.spawn(MaterialMeshBundle {
-        mesh: meshes.add(Mesh::from(LineList {
-            lines: vec![(Vec3::ZERO, Vec3::new(x, y, z))],
-        })),
-        transform: Transform::from_xyz(0.0, 0.0, 0.0),
-        material: line_materials.add(LineMaterial {
-            color: Color::YELLOW,
-        }),
-        ..default()
-    });
+    commands.spawn((
+        MaterialMeshBundle {
+            mesh: meshes.add(Mesh::from(north_line(&field))),
+            transform: Transform::from_xyz(0.0, 0.0, 0.0),
+            material: line_materials.add(LineMaterial {
+                color: Color::YELLOW,
+            }),
+            ..default()
+        },
+        North,
+    ));
 }
 
 #[derive(Default, AsBindGroup, TypeUuid, Debug, Clone)]
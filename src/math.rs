@@ -1,17 +1,59 @@
-#![allow(unused)]
-use nalgebra::{DMatrix, Matrix3, Matrix3x1, Matrix6, OVector, SymmetricEigen, Vector3, U3};
+use nalgebra::{
+    DMatrix, DVector, Matrix3, Matrix3x1, Matrix6, SMatrix, SVector, SymmetricEigen, Vector3,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
-/// Returns principal square root of the 3x3 matrix
-fn sqrt_m(matrix: &Matrix3<f64>) -> Matrix3<f64> {
+use crate::refine::Refinement;
+
+/// Minimal number of samples to determine the 10 coefficients of a quadric
+pub const MIN_SAMPLES: usize = 10;
+
+/// Reasons why samples could not be turned into a calibration
+#[derive(Debug, Clone, PartialEq)]
+pub enum FitError {
+    /// Fewer samples than unknowns of the quadric
+    TooFewSamples { got: usize, need: usize },
+    /// Samples do not span 3D space (e.g. board was rotated in one plane only)
+    DegenerateGeometry,
+    /// Fitted quadric is not an ellipsoid (M is not positive definite)
+    NotEllipsoid,
+    /// Square root of a negative value was required
+    NegativeRadicand(f64),
+}
+
+impl fmt::Display for FitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FitError::TooFewSamples { got, need } => {
+                write!(f, "too few samples: got {}, need at least {}", got, need)
+            }
+            FitError::DegenerateGeometry => {
+                write!(f, "degenerate geometry: samples are coplanar or collinear")
+            }
+            FitError::NotEllipsoid => write!(f, "fitted surface is not an ellipsoid"),
+            FitError::NegativeRadicand(v) => write!(f, "negative radicand: {}", v),
+        }
+    }
+}
+
+impl std::error::Error for FitError {}
+
+/// Returns principal square root of the 3x3 symmetric positive definite matrix
+fn sqrt_m(matrix: &Matrix3<f64>) -> Result<Matrix3<f64>, FitError> {
     let e = matrix.symmetric_eigen();
     let d = e.eigenvalues;
     let q = e.eigenvectors;
     let mut sqrt_d = Vector3::new(0.0, 0.0, 0.0);
     for i in 0..3 {
+        if d[i] < 0.0 {
+            return Err(FitError::NegativeRadicand(d[i]));
+        }
         sqrt_d[i] = d[i].sqrt();
     }
     let d_sqrt = Matrix3::from_diagonal(&sqrt_d);
-    q * d_sqrt * q.try_inverse().unwrap()
+    // eigenvectors of a symmetric matrix are orthonormal, so inverse is transpose
+    Ok(q * d_sqrt * q.transpose())
 }
 
 /// Returns a_1 and b to be applied to raw sensor data
@@ -20,62 +62,511 @@ pub fn ellipsoid_to_calibration(
     n: Vector3<f64>,
     d: f64,
     f: f64,
-) -> (Matrix3<f64>, Vector3<f64>) {
-    let m_1 = m.try_inverse().expect("m to be inversible");
+) -> Result<(Matrix3<f64>, Vector3<f64>), FitError> {
+    if m.symmetric_eigenvalues().min() <= 0.0 {
+        return Err(FitError::NotEllipsoid);
+    }
+    let m_1 = m.try_inverse().ok_or(FitError::NotEllipsoid)?;
     let b = -(m_1 * n);
-    let a_1 = (f / ((n.transpose() * (m_1 * n))[0] - d).sqrt()) * sqrt_m(&m);
-    return (a_1, b);
+    let radicand = (n.transpose() * (m_1 * n))[0] - d;
+    if radicand <= 0.0 || !radicand.is_finite() {
+        return Err(FitError::NegativeRadicand(radicand));
+    }
+    let a_1 = (f / radicand.sqrt()) * sqrt_m(&m)?;
+    Ok((a_1, b))
 }
 
 /// Fits ellipsoid to set of points
-pub fn ellipsoid_fit(s: &Vec<[f64; 3]>) -> (Matrix3<f64>, Vector3<f64>, f64) {
-    let n = s.len();
-    let mut d = DMatrix::<f64>::zeros(10, n);
-    for j in 0..n {
-        let s_j = OVector::<f64, U3>::from_row_slice(&s[j]);
-        d[(0, j)] = s_j[0].powi(2);
-        d[(1, j)] = s_j[1].powi(2);
-        d[(2, j)] = s_j[2].powi(2);
-        d[(3, j)] = 2.0 * s_j[1] * s_j[2];
-        d[(4, j)] = 2.0 * s_j[0] * s_j[2];
-        d[(5, j)] = 2.0 * s_j[0] * s_j[1];
-        d[(6, j)] = 2.0 * s_j[0];
-        d[(7, j)] = 2.0 * s_j[1];
-        d[(8, j)] = 2.0 * s_j[2];
-        d[(9, j)] = 1.0;
-    }
-    let s = d.clone() * d.transpose();
-    let s_11 = s.fixed_view::<6, 6>(0, 0);
-    let s_12 = s.fixed_view::<6, 4>(0, 6);
-    let s_21 = s.fixed_view::<4, 6>(6, 0);
-    let s_22 = s.fixed_view::<4, 4>(6, 6);
-    let c = Matrix6::new(
-        -1., 1., 1., 0., 0., 0., 1., -1., 1., 0., 0., 0., 1., 1., -1., 0., 0., 0., 0., 0., 0., -4.,
-        0., 0., 0., 0., 0., 0., -4., 0., 0., 0., 0., 0., 0., -4.,
-    );
-    let inv_c = c.try_inverse().expect("C is not invertible");
-    let inv_s_22 = s_22.try_inverse().expect("S_22 is not invertible");
-
-    let e = inv_c * (s_11 - (s_12 * inv_s_22 * s_21));
-    let eigendec = SymmetricEigen::new(e);
-    let e_w = eigendec.eigenvalues;
-    let e_v = eigendec.eigenvectors;
-
-    let (argmax, _) = e_w.argmax();
-    let p_v_1 = e_v.column(argmax);
-    let v_1 = if p_v_1[0] < 0.0 {
-        -1. * p_v_1
+pub fn ellipsoid_fit(s: &[[f64; 3]]) -> Result<(Matrix3<f64>, Vector3<f64>, f64), FitError> {
+    weighted_ellipsoid_fit(s, &vec![1.0; s.len()])
+}
+
+/// Fits ellipsoid to set of points, the squared algebraic error of `s[j]` is scaled by `w[j]`
+pub fn weighted_ellipsoid_fit(
+    s: &[[f64; 3]],
+    w: &[f64],
+) -> Result<(Matrix3<f64>, Vector3<f64>, f64), FitError> {
+    let mut scatter = Scatter::default();
+    for (s_j, w_j) in s.iter().zip(w) {
+        scatter.add_weighted(s_j, *w_j);
+    }
+    scatter.fit()
+}
+
+/// Running sum of `D D^T` over samples, the ellipsoid fit only depends on it
+///
+/// Samples can be added one by one and the fit repeated at any time at a constant cost.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scatter {
+    s: SMatrix<f64, 10, 10>,
+    count: usize,
+}
+
+impl Default for Scatter {
+    fn default() -> Self {
+        Scatter {
+            s: SMatrix::zeros(),
+            count: 0,
+        }
+    }
+}
+
+impl Scatter {
+    pub fn add(&mut self, s_j: &[f64; 3]) {
+        self.add_weighted(s_j, 1.0);
+    }
+
+    pub fn add_weighted(&mut self, s_j: &[f64; 3], w: f64) {
+        let [x, y, z] = *s_j;
+        let d = SVector::<f64, 10>::from([
+            x * x,
+            y * y,
+            z * z,
+            2.0 * y * z,
+            2.0 * x * z,
+            2.0 * x * y,
+            2.0 * x,
+            2.0 * y,
+            2.0 * z,
+            1.0,
+        ]);
+        self.s += d * d.transpose() * w;
+        self.count += 1;
+    }
+
+    /// Number of samples added
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Ellipsoid of all samples added so far
+    pub fn fit(&self) -> Result<(Matrix3<f64>, Vector3<f64>, f64), FitError> {
+        if self.count < MIN_SAMPLES {
+            return Err(FitError::TooFewSamples {
+                got: self.count,
+                need: MIN_SAMPLES,
+            });
+        }
+        let s = &self.s;
+        let s_11 = s.fixed_view::<6, 6>(0, 0);
+        let s_12 = s.fixed_view::<6, 4>(0, 6);
+        let s_21 = s.fixed_view::<4, 6>(6, 0);
+        let s_22 = s.fixed_view::<4, 4>(6, 6);
+        let c = Matrix6::new(
+            -1., 1., 1., 0., 0., 0., 1., -1., 1., 0., 0., 0., 1., 1., -1., 0., 0., 0., 0., 0., 0.,
+            -4., 0., 0., 0., 0., 0., 0., -4., 0., 0., 0., 0., 0., 0., -4.,
+        );
+        let inv_c = c.try_inverse().expect("C is not invertible");
+        let inv_s_22 = s_22.try_inverse().ok_or(FitError::DegenerateGeometry)?;
+
+        // Eigenvectors of C^-1 * S are found from the symmetric L^T * C^-1 * L, S = L * L^T
+        let schur = s_11 - (s_12 * inv_s_22 * s_21);
+        if !schur.iter().all(|x| x.is_finite()) {
+            return Err(FitError::DegenerateGeometry);
+        }
+        let l = schur.cholesky().ok_or(FitError::DegenerateGeometry)?.l();
+        let e = l.transpose() * inv_c * l;
+        let eigendec = SymmetricEigen::new((e + e.transpose()) * 0.5);
+        let e_w = eigendec.eigenvalues;
+        let e_v = eigendec.eigenvectors;
+
+        let (argmax, max) = e_w.argmax();
+        if max <= 0.0 {
+            return Err(FitError::NotEllipsoid);
+        }
+        let p_v_1 = l
+            .transpose()
+            .solve_upper_triangular(&e_v.column(argmax).into_owned())
+            .ok_or(FitError::DegenerateGeometry)?;
+        let v_1 = if p_v_1[0] < 0.0 {
+            -1. * p_v_1
+        } else {
+            1. * p_v_1
+        };
+
+        let v_2 = (-inv_s_22 * s_21) * v_1;
+        // v_1 holds x^2, y^2, z^2, 2yz, 2xz, 2xy coefficients
+        let m = Matrix3::new(
+            v_1[0], v_1[5], v_1[4], v_1[5], v_1[1], v_1[3], v_1[4], v_1[3], v_1[2],
+        );
+        let n = Vector3::new(v_2[0], v_2[1], v_2[2]);
+        let d = v_2[3];
+        Ok((m, n, d))
+    }
+}
+
+/// Solves `rows * x = rhs` in the least squares sense
+fn least_squares(rows: DMatrix<f64>, rhs: DVector<f64>) -> Result<DVector<f64>, FitError> {
+    let svd = rows.svd(true, true);
+    let (min, max) = (svd.singular_values.min(), svd.singular_values.max());
+    if min <= max * 1e-12 || !min.is_finite() {
+        return Err(FitError::DegenerateGeometry);
+    }
+    svd.solve(&rhs, 0.0)
+        .map_err(|_| FitError::DegenerateGeometry)
+}
+
+/// Fits sphere `|s - b|^2 = r^2`, returned as quadric with `M = I`
+///
+/// If the samples lie in a plane the offset along its normal cannot be determined, a
+/// circle is fitted in the plane instead. `b` is put in the plane and the radius is that
+/// of the circle, [`FitReport::undetermined`] tells the direction.
+pub fn weighted_sphere_fit(
+    s: &[[f64; 3]],
+    w: &[f64],
+) -> Result<(Matrix3<f64>, Vector3<f64>, f64), FitError> {
+    const UNKNOWNS: usize = 4;
+    if s.len() < UNKNOWNS {
+        return Err(FitError::TooFewSamples {
+            got: s.len(),
+            need: UNKNOWNS,
+        });
+    }
+    let (mean, covariance) = moments(s, w);
+    if let Some(normal) = flat_direction(&covariance) {
+        return weighted_circle_fit(s, w, mean, normal);
+    }
+    // |s|^2 = 2 b.s + c, c = r^2 - |b|^2
+    let rows = DMatrix::from_fn(s.len(), UNKNOWNS, |j, i| {
+        w[j].sqrt() * if i < 3 { s[j][i] } else { 1.0 }
+    });
+    let rhs = DVector::from_fn(s.len(), |j, _| {
+        w[j].sqrt() * Vector3::from(s[j]).norm_squared()
+    });
+    let x = least_squares(rows, rhs)?;
+    let b = Vector3::new(x[0], x[1], x[2]) / 2.0;
+    Ok((Matrix3::identity(), -b, -x[3]))
+}
+
+/// Fits a circle to samples in the plane through `mean` with `normal`, as a sphere
+/// centred on the circle
+fn weighted_circle_fit(
+    s: &[[f64; 3]],
+    w: &[f64],
+    mean: Vector3<f64>,
+    normal: Vector3<f64>,
+) -> Result<(Matrix3<f64>, Vector3<f64>, f64), FitError> {
+    let u = normal.cross(&Vector3::x());
+    let u = if u.norm() < 0.5 {
+        normal.cross(&Vector3::y())
     } else {
-        1. * p_v_1
+        u
+    }
+    .normalize();
+    let v = normal.cross(&u);
+    let plane = |s_j: &[f64; 3]| {
+        let d = Vector3::from(*s_j) - mean;
+        (d.dot(&u), d.dot(&v))
     };
+    // |p|^2 = 2 c.p + k, k = r^2 - |c|^2
+    let rows = DMatrix::from_fn(s.len(), 3, |j, i| {
+        let (x, y) = plane(&s[j]);
+        w[j].sqrt() * [x, y, 1.0][i]
+    });
+    let rhs = DVector::from_fn(s.len(), |j, _| {
+        let (x, y) = plane(&s[j]);
+        w[j].sqrt() * (x * x + y * y)
+    });
+    let x = least_squares(rows, rhs)?;
+    let c = Vector3::new(x[0], x[1], 0.0) / 2.0;
+    let r_2 = x[2] + c.norm_squared();
+    let b = mean + u * c.x + v * c.y;
+    Ok((Matrix3::identity(), -b, b.norm_squared() - r_2))
+}
+
+/// Fits ellipsoid with axes along the sensor axes, `M` is diagonal
+pub fn weighted_diagonal_fit(
+    s: &[[f64; 3]],
+    w: &[f64],
+) -> Result<(Matrix3<f64>, Vector3<f64>, f64), FitError> {
+    const UNKNOWNS: usize = 6;
+    if s.len() < UNKNOWNS {
+        return Err(FitError::TooFewSamples {
+            got: s.len(),
+            need: UNKNOWNS,
+        });
+    }
+    // A x^2 + B y^2 + C z^2 + 2G x + 2H y + 2I z = 1
+    let rows = DMatrix::from_fn(s.len(), UNKNOWNS, |j, i| {
+        w[j].sqrt()
+            * if i < 3 {
+                s[j][i].powi(2)
+            } else {
+                2.0 * s[j][i - 3]
+            }
+    });
+    let rhs = DVector::from_fn(s.len(), |j, _| w[j].sqrt());
+    let x = least_squares(rows, rhs)?;
+    let m = Matrix3::from_diagonal(&Vector3::new(x[0], x[1], x[2]));
+    Ok((m, Vector3::new(x[3], x[4], x[5]), -1.0))
+}
+
+/// What the calibration corrects, from least to most parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FitModel {
+    /// Hard iron offset and a common scale, fits a sphere
+    Offset,
+    /// Offset and a scale per axis, fits an axis aligned ellipsoid
+    Diagonal,
+    /// Offset and symmetric soft iron, fits any ellipsoid
+    Full,
+}
+
+impl FitModel {
+    pub const ALL: [FitModel; 3] = [FitModel::Offset, FitModel::Diagonal, FitModel::Full];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FitModel::Offset => "offset",
+            FitModel::Diagonal => "diagonal",
+            FitModel::Full => "full",
+        }
+    }
+
+    /// Fitted quadric, the squared error of `s[j]` is scaled by `w[j]`
+    pub fn fit(
+        &self,
+        s: &[[f64; 3]],
+        w: &[f64],
+    ) -> Result<(Matrix3<f64>, Vector3<f64>, f64), FitError> {
+        match self {
+            FitModel::Offset => weighted_sphere_fit(s, w),
+            FitModel::Diagonal => weighted_diagonal_fit(s, w),
+            FitModel::Full => weighted_ellipsoid_fit(s, w),
+        }
+    }
+
+    /// The next model with fewer parameters
+    pub fn simpler(&self) -> Option<FitModel> {
+        match self {
+            FitModel::Offset => None,
+            FitModel::Diagonal => Some(FitModel::Offset),
+            FitModel::Full => Some(FitModel::Diagonal),
+        }
+    }
+}
+
+impl std::str::FromStr for FitModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FitModel::ALL
+            .into_iter()
+            .find(|m| m.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = FitModel::ALL.iter().map(|m| m.name()).collect();
+                format!("unknown model {}, expected one of {}", s, names.join(", "))
+            })
+    }
+}
+
+/// Smallest thickness of the sample cloud to fit a [`FitModel::Full`] model
+pub const FULL_MIN_THICKNESS: f64 = 0.3;
+/// Smallest thickness of the sample cloud to fit a [`FitModel::Diagonal`] model
+pub const DIAGONAL_MIN_THICKNESS: f64 = 0.2;
+
+/// Largest thickness of samples that are taken to lie in a plane, about 3 degrees off it
+pub const PLANAR_MAX_THICKNESS: f64 = 0.05;
+
+/// Weighted mean and covariance of the samples
+fn moments(s: &[[f64; 3]], w: &[f64]) -> (Vector3<f64>, Matrix3<f64>) {
+    let total = w.iter().sum::<f64>().max(f64::MIN_POSITIVE);
+    let mean = s
+        .iter()
+        .zip(w)
+        .map(|(s_j, w_j)| Vector3::from(*s_j) * *w_j)
+        .sum::<Vector3<f64>>()
+        / total;
+    let covariance = s
+        .iter()
+        .zip(w)
+        .map(|(s_j, w_j)| {
+            let d = Vector3::from(*s_j) - mean;
+            d * d.transpose() * *w_j
+        })
+        .sum::<Matrix3<f64>>()
+        / total;
+    (mean, covariance)
+}
 
-    let v_2 = (-inv_s_22 * s_21) * v_1;
-    let m = Matrix3::new(
-        v_1[0], v_1[3], v_1[4], v_1[3], v_1[1], v_1[5], v_1[4], v_1[5], v_1[2],
-    );
-    let n = Vector3::new(v_2[0], v_2[1], v_2[2]);
-    let d = v_2[3];
-    (m, n, d)
+fn thickness_of(covariance: &Matrix3<f64>) -> f64 {
+    let eigenvalues = covariance.symmetric_eigenvalues();
+    (eigenvalues.min().max(0.0) / eigenvalues.max()).sqrt()
+}
+
+/// Normal of the plane the samples lie in, `None` if they are thicker than
+/// [`PLANAR_MAX_THICKNESS`]
+fn flat_direction(covariance: &Matrix3<f64>) -> Option<Vector3<f64>> {
+    if thickness_of(covariance) >= PLANAR_MAX_THICKNESS {
+        return None;
+    }
+    let eigen = covariance.symmetric_eigen();
+    let (min, _) = eigen.eigenvalues.argmin();
+    Some(eigen.eigenvectors.column(min).into_owned())
+}
+
+/// `sqrt` of smallest over largest eigenvalue of the sample covariance
+///
+/// 1 for a covered sphere, 0.5 for a hemisphere and close to 0 when the board was
+/// only rotated in one plane.
+pub fn thickness(s: &[[f64; 3]]) -> f64 {
+    thickness_of(&moments(s, &vec![1.0; s.len()]).1)
+}
+
+/// Direction along which the offset cannot be determined because the samples lie in a plane
+pub fn undetermined_direction(s: &[[f64; 3]]) -> Option<Vector3<f64>> {
+    flat_direction(&moments(s, &vec![1.0; s.len()]).1)
+}
+
+/// The most detailed model the spread of samples supports
+pub fn choose_model(s: &[[f64; 3]]) -> FitModel {
+    match thickness(s) {
+        t if t >= FULL_MIN_THICKNESS => FitModel::Full,
+        t if t >= DIAGONAL_MIN_THICKNESS => FitModel::Diagonal,
+        _ => FitModel::Offset,
+    }
+}
+
+/// Settings of [`robust_ellipsoid_fit`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RobustOptions {
+    /// Number of random subsets tried
+    pub iterations: usize,
+    /// Samples in each subset
+    pub subset: usize,
+    /// Largest `|a_1 (s - b)| / F - 1` of an inlier
+    pub threshold: f64,
+    /// Relative residual above which IRLS weights fall off as `huber / |r|`
+    pub huber: f64,
+    /// Reweighting rounds after RANSAC
+    pub irls_iterations: usize,
+}
+
+impl Default for RobustOptions {
+    fn default() -> Self {
+        RobustOptions {
+            iterations: 200,
+            subset: 2 * MIN_SAMPLES,
+            threshold: 0.05,
+            huber: 0.01,
+            irls_iterations: 5,
+        }
+    }
+}
+
+/// Ellipsoid fitted to the inliers and indices of the rejected samples
+#[derive(Debug, Clone)]
+pub struct RobustFit {
+    pub m: Matrix3<f64>,
+    pub n: Vector3<f64>,
+    pub d: f64,
+    pub rejected: Vec<usize>,
+}
+
+/// `|a_1 (s - b)| - 1` of every sample for the calibration onto the unit sphere
+fn relative_residuals(
+    s: &[[f64; 3]],
+    (m, n, d): (Matrix3<f64>, Vector3<f64>, f64),
+) -> Result<Vec<f64>, FitError> {
+    let (a_1, b) = ellipsoid_to_calibration(m, n, d, 1.0)?;
+    Ok(s.iter()
+        .map(|s_j| (a_1 * (Vector3::from(*s_j) - b)).norm() - 1.0)
+        .collect())
+}
+
+/// xorshift64, the fit must not depend on an external source of randomness
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+/// Fits `model` ignoring samples far off the surface
+///
+/// RANSAC picks the model of random subsets with most inliers, which is then refined by
+/// Huber weighted least squares over the inliers.
+pub fn robust_ellipsoid_fit(
+    s: &[[f64; 3]],
+    model: FitModel,
+    options: &RobustOptions,
+) -> Result<RobustFit, FitError> {
+    let count_inliers = |r: &[f64]| r.iter().filter(|r| r.abs() <= options.threshold).count();
+    let full = model
+        .fit(s, &vec![1.0; s.len()])
+        .and_then(|model| Ok((model, relative_residuals(s, model)?)));
+    let mut best = full
+        .clone()
+        .ok()
+        .map(|(model, r)| (model, count_inliers(&r)));
+    if s.len() > options.subset {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        let mut subset = Vec::with_capacity(options.subset);
+        let ones = vec![1.0; options.subset];
+        for _ in 0..options.iterations {
+            subset.clear();
+            for _ in 0..options.subset {
+                subset.push(s[rng.below(s.len())]);
+            }
+            // Unlucky subsets are degenerate, just try the next one
+            let Ok(quadric) = model.fit(&subset, &ones) else {
+                continue;
+            };
+            let Ok(r) = relative_residuals(s, quadric) else {
+                continue;
+            };
+            let inliers = count_inliers(&r);
+            let better = match best {
+                Some((_, most)) => inliers > most,
+                None => true,
+            };
+            if better {
+                best = Some((quadric, inliers));
+            }
+        }
+    }
+    let Some((mut best, _)) = best else {
+        return Err(full.expect_err("full fit failed if no model was found"));
+    };
+
+    let mut r = relative_residuals(s, best)?;
+    for _ in 0..options.irls_iterations {
+        let w: Vec<f64> = r
+            .iter()
+            .map(|r| match r.abs() {
+                r if r > options.threshold => 0.0,
+                r if r > options.huber => options.huber / r,
+                _ => 1.0,
+            })
+            .collect();
+        // Keep the last good model if reweighting leaves too little to fit
+        let Ok(refined) = model.fit(s, &w) else {
+            break;
+        };
+        let Ok(refined_r) = relative_residuals(s, refined) else {
+            break;
+        };
+        best = refined;
+        r = refined_r;
+    }
+    let (m, n, d) = best;
+    Ok(RobustFit {
+        m,
+        n,
+        d,
+        rejected: (0..s.len())
+            .filter(|&j| r[j].abs() > options.threshold)
+            .collect(),
+    })
 }
 
 /// Transformation of a single sample
@@ -85,6 +576,282 @@ pub fn calibrated_sample(
     b: &Vector3<f32>,
 ) -> Matrix3x1<f32> {
     let s = Matrix3x1::from_row_slice(sample);
-    let transformed_s = a_1 * (s - b);
-    transformed_s
+    a_1 * (s - b)
+}
+
+/// How well a calibration maps samples onto the sphere of radius `field`
+///
+/// Shape metrics are derived from `a_1`, `M` of the fitted ellipsoid is proportional
+/// to `a_1^T a_1`. Fields after `max` are missing in reports saved by older versions.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FitReport {
+    /// Expected field strength the calibration was fitted to
+    pub field: f64,
+    /// Number of samples
+    pub samples: usize,
+    /// RMS of `|a_1 (s - b)| - field`
+    pub rms: f64,
+    /// Largest absolute `|a_1 (s - b)| - field`
+    pub max: f64,
+    /// Gain applied to each sensor axis, norms of the columns of `a_1`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<[f64; 3]>,
+    /// Deviation from 90 degrees of the calibrated xy, xz and yz sensor axes, degrees
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub non_orthogonality: Option<[f64; 3]>,
+    /// Ratio of largest to smallest eigenvalue of `M`, `None` if `M` is singular or the
+    /// report has no [`FitReport::spread`]
+    #[serde(default)]
+    pub condition: Option<f64>,
+    /// Difference of largest and smallest eigenvalue of `M` relative to their mean
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spread: Option<f64>,
+    /// Model the calibration was fitted with, missing in reports of older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<FitModel>,
+    /// Set if the calibration was refined by [`crate::refine::refine`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refinement: Option<Refinement>,
+    /// Unit vector along which `b` is not determined, set if the samples lie in a plane
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub undetermined: Option<[f64; 3]>,
+}
+
+impl FitReport {
+    pub fn new(s: &[[f64; 3]], a_1: &Matrix3<f64>, b: &Vector3<f64>, field: f64) -> Self {
+        let residuals: Vec<f64> = s
+            .iter()
+            .map(|s_j| (a_1 * (Vector3::from(*s_j) - b)).norm() - field)
+            .collect();
+        let n = residuals.len().max(1) as f64;
+
+        let axes = [a_1.column(0), a_1.column(1), a_1.column(2)];
+        let scale = axes.map(|a| a.norm());
+        let skew = |i: usize, j: usize| 90.0 - axes[i].angle(&axes[j]).to_degrees();
+        let eigenvalues = (a_1.transpose() * a_1).symmetric_eigenvalues();
+        let (min, max) = (eigenvalues.min(), eigenvalues.max());
+        FitReport {
+            field,
+            samples: s.len(),
+            rms: (residuals.iter().map(|r| r * r).sum::<f64>() / n).sqrt(),
+            max: residuals.iter().fold(0.0, |m, r| r.abs().max(m)),
+            scale: Some(scale),
+            non_orthogonality: Some([skew(0, 1), skew(0, 2), skew(1, 2)]),
+            condition: Some(max / min).filter(|c| min > 0.0 && c.is_finite()),
+            spread: Some((max - min) / eigenvalues.mean()),
+            model: None,
+            refinement: None,
+            undetermined: undetermined_direction(s).map(Into::into),
+        }
+    }
+}
+
+impl fmt::Display for FitReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "F = {:.3} from {} samples", self.field, self.samples)?;
+        if let Some(model) = self.model {
+            write!(f, ", {} model", model.name())?;
+        }
+        write!(
+            f,
+            "\n|a_1 (s - b)| - F: rms {:.3}, max {:.3}",
+            self.rms, self.max
+        )?;
+        // Reports of older versions have no shape metrics
+        if let Some([x, y, z]) = self.scale {
+            write!(f, "\nscale x {:.4}, y {:.4}, z {:.4}", x, y, z)?;
+        }
+        if let Some([xy, xz, yz]) = self.non_orthogonality {
+            write!(
+                f,
+                "\nnon-orthogonality xy {:.3}, xz {:.3}, yz {:.3} deg",
+                xy, xz, yz
+            )?;
+        }
+        if let Some(spread) = self.spread {
+            match self.condition {
+                Some(condition) => write!(f, "\ncond(M) {:.4}", condition)?,
+                None => write!(f, "\nM is singular")?,
+            }
+            write!(f, ", eigenvalue spread {:.4}", spread)?;
+        }
+        if let Some([x, y, z]) = self.undetermined {
+            write!(
+                f,
+                "\nsamples lie in a plane, b along [{:.3}, {:.3}, {:.3}] is undetermined",
+                x, y, z
+            )?;
+        }
+        if let Some(refinement) = &self.refinement {
+            write!(
+                f,
+                "\nrefined in {} iterations, cost {:.3} -> {:.3}",
+                refinement.iterations, refinement.initial_cost, refinement.cost
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::{raw, sphere, truth, Noise, FIELD};
+
+    #[test]
+    fn robust_fit_rejects_planted_outliers() {
+        let (a_1, b) = truth();
+        let mut noise = Noise::new(1);
+        let mut s = raw(&sphere(300), &a_1, &b);
+        for s_j in &mut s {
+            for c in s_j.iter_mut() {
+                *c += 2.0 * noise.next();
+            }
+        }
+        // Every tenth sample taken next to a magnet, 20 to 40% off the surface
+        let planted: Vec<usize> = (0..s.len()).step_by(10).collect();
+        for &j in &planted {
+            let scale = 1.0 + (0.3 + 0.1 * noise.next()) * noise.next().signum();
+            s[j] = (b + (Vector3::from(s[j]) - b) * scale).into();
+        }
+        let fit = robust_ellipsoid_fit(&s, FitModel::Full, &RobustOptions::default()).unwrap();
+        assert_eq!(fit.rejected, planted);
+        let (_, fitted_b) = ellipsoid_to_calibration(fit.m, fit.n, fit.d, FIELD).unwrap();
+        assert!((fitted_b - b).norm() < 1.0, "{}", fitted_b);
+
+        // Without rejection the outliers pull the fit away
+        let (m, n, d) = ellipsoid_fit(&s).unwrap();
+        let (_, plain_b) = ellipsoid_to_calibration(m, n, d, FIELD).unwrap();
+        assert!((plain_b - b).norm() > (fitted_b - b).norm());
+    }
+
+    #[test]
+    fn planar_offset_fit_reports_undetermined_direction() {
+        let (_, b) = truth();
+        let mut noise = Noise::new(7);
+        // Board turned about z only, the field inclined 60 degrees
+        let inclination = 60f64.to_radians();
+        let s: Vec<[f64; 3]> = (0..200)
+            .map(|i| {
+                let heading = i as f64 * 0.1;
+                let u = Vector3::new(
+                    inclination.cos() * heading.cos(),
+                    inclination.cos() * heading.sin(),
+                    inclination.sin(),
+                );
+                (u * FIELD + b + noise.vector() * 3.0).into()
+            })
+            .collect();
+        assert!(thickness(&s) < PLANAR_MAX_THICKNESS);
+        assert_eq!(choose_model(&s), FitModel::Offset);
+
+        let (m, n, d) = weighted_sphere_fit(&s, &vec![1.0; s.len()]).unwrap();
+        let (a_1, fitted_b) = ellipsoid_to_calibration(m, n, d, FIELD).unwrap();
+        assert!((fitted_b.xy() - b.xy()).norm() < 1.0, "{}", fitted_b);
+        // b is put in the plane of the samples rather than anywhere along z
+        let plane = b.z + FIELD * inclination.sin();
+        assert!((fitted_b.z - plane).abs() < 1.0, "{}", fitted_b);
+
+        let report = FitReport::new(&s, &a_1, &fitted_b, FIELD);
+        let [x, y, z] = report.undetermined.unwrap();
+        assert!(x.abs() < 0.01 && y.abs() < 0.01 && (z.abs() - 1.0).abs() < 1e-4);
+        assert!(report.to_string().contains("undetermined"));
+    }
+
+    #[test]
+    fn spread_samples_are_determined() {
+        let s = raw(&sphere(100), &truth().0, &Vector3::zeros());
+        assert_eq!(undetermined_direction(&s), None);
+    }
+
+    #[test]
+    fn singular_report_round_trips() {
+        let a_1 = Matrix3::from_diagonal(&Vector3::new(1.0, 1.0, 0.0));
+        let s = raw(&sphere(20), &Matrix3::identity(), &Vector3::zeros());
+        let report = FitReport::new(&s, &a_1, &Vector3::zeros(), FIELD);
+        assert_eq!(report.condition, None);
+        let json = serde_json::to_string(&report).unwrap();
+        let loaded: FitReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.condition, None);
+        assert!(report.to_string().contains("M is singular"));
+    }
+
+    #[test]
+    fn report_of_older_version_has_no_shape_metrics() {
+        let json = r#"{"field": 500.0, "samples": 200, "rms": 1.5, "max": 4.0}"#;
+        let report: FitReport = serde_json::from_str(json).unwrap();
+        assert_eq!(report.scale, None);
+        assert_eq!(report.non_orthogonality, None);
+        assert_eq!(report.spread, None);
+        assert_eq!(
+            report.to_string(),
+            "F = 500.000 from 200 samples\n|a_1 (s - b)| - F: rms 1.500, max 4.000"
+        );
+        let saved = serde_json::to_string(&report).unwrap();
+        assert!(
+            !saved.contains("scale") && !saved.contains("spread"),
+            "{}",
+            saved
+        );
+    }
+
+    #[test]
+    fn ellipsoid_fit_recovers_rotated_ellipsoid() {
+        let (a_1, b) = truth();
+        let s = raw(&sphere(200), &a_1, &b);
+        let (m, n, d) = ellipsoid_fit(&s).unwrap();
+        let (fitted_a_1, fitted_b) = ellipsoid_to_calibration(m, n, d, FIELD).unwrap();
+        assert!((fitted_a_1 - a_1).norm() < 1e-6, "{}", fitted_a_1);
+        assert!((fitted_b - b).norm() < 1e-6, "{}", fitted_b);
+    }
+
+    #[test]
+    fn fewer_samples_than_unknowns_are_too_few() {
+        let s = raw(
+            &sphere(MIN_SAMPLES - 1),
+            &Matrix3::identity(),
+            &Vector3::zeros(),
+        );
+        assert_eq!(
+            ellipsoid_fit(&s),
+            Err(FitError::TooFewSamples {
+                got: MIN_SAMPLES - 1,
+                need: MIN_SAMPLES
+            })
+        );
+    }
+
+    #[test]
+    fn coplanar_samples_are_degenerate() {
+        let s: Vec<[f64; 3]> = sphere(50)
+            .iter()
+            .map(|u| [u.x * FIELD, u.y * FIELD, 0.0])
+            .collect();
+        assert_eq!(ellipsoid_fit(&s), Err(FitError::DegenerateGeometry));
+    }
+
+    #[test]
+    fn hyperboloid_is_not_an_ellipsoid() {
+        // x^2 + y^2 - z^2 = F^2. The full fit is constrained to ellipsoids, the axis
+        // aligned one is not
+        let s: Vec<[f64; 3]> = (0..100)
+            .map(|i| {
+                let z = FIELD * (i as f64 / 50.0 - 1.0);
+                let r = (FIELD * FIELD + z * z).sqrt();
+                let phi = 2.4 * i as f64;
+                [r * phi.cos(), r * phi.sin(), z]
+            })
+            .collect();
+        let fit = FitModel::Diagonal
+            .fit(&s, &vec![1.0; s.len()])
+            .and_then(|(m, n, d)| ellipsoid_to_calibration(m, n, d, FIELD));
+        assert_eq!(fit, Err(FitError::NotEllipsoid));
+    }
+
+    #[test]
+    fn imaginary_sphere_has_negative_radicand() {
+        // |s|^2 + 1 = 0 has no real points
+        let fit = ellipsoid_to_calibration(Matrix3::identity(), Vector3::zeros(), 1.0, FIELD);
+        assert_eq!(fit, Err(FitError::NegativeRadicand(-1.0)));
+    }
 }
@@ -0,0 +1,182 @@
+//! Time series of the last samples, to spot disturbances and dropouts while collecting
+//!
+//! Time is the sum of the `dt` the device reports. Plots follow new samples until they are
+//! zoomed or dragged, double click resets them.
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use egui::plot::{HLine, Legend, Line, Plot, PlotPoints};
+
+use bevy_mag::{Calibration, Sample, Samples};
+
+use crate::{Field, SampleKind};
+
+const AXES: [&str; 3] = ["x", "y", "z"];
+const PLOT_HEIGHT: f32 = 120.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Series {
+    RawMag,
+    CalMag,
+    Accel,
+    Gyro,
+    Norm,
+    Dt,
+}
+
+impl Series {
+    const ALL: [Series; 6] = [
+        Series::RawMag,
+        Series::CalMag,
+        Series::Accel,
+        Series::Gyro,
+        Series::Norm,
+        Series::Dt,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Series::RawMag => "raw_mag",
+            Series::CalMag => "cal_mag",
+            Series::Accel => "accel",
+            Series::Gyro => "gyro",
+            Series::Norm => "|calibrated mag|",
+            Series::Dt => "dt",
+        }
+    }
+
+    /// Values per axis, empty for series that are a single value
+    fn axes<'a>(&self, sample: &'a Sample) -> &'a [f32] {
+        match self {
+            Series::RawMag => &sample.raw_mag,
+            Series::CalMag => &sample.cal_mag,
+            Series::Accel => &sample.accel,
+            Series::Gyro => &sample.gyro,
+            Series::Norm | Series::Dt => &[],
+        }
+    }
+}
+
+#[derive(Resource)]
+struct Plots {
+    /// Seconds shown
+    window: f64,
+    /// Number of samples shown while paused
+    paused: Option<usize>,
+    shown: Vec<Series>,
+}
+
+impl Default for Plots {
+    fn default() -> Self {
+        Plots {
+            window: 10.0,
+            paused: None,
+            shown: vec![Series::RawMag, Series::Norm, Series::Dt],
+        }
+    }
+}
+
+pub struct PlotsPlugin;
+
+impl Plugin for PlotsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Plots>().add_system(draw_plots_ui);
+    }
+}
+
+fn draw_plots_ui(
+    mut contexts: EguiContexts,
+    mut plots: ResMut<Plots>,
+    history: Res<Samples>,
+    calibration: Res<Calibration>,
+    kind: Res<SampleKind>,
+    field: Res<Field>,
+) {
+    egui::Window::new("Plots")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                let label = if plots.paused.is_some() {
+                    "Resume"
+                } else {
+                    "Pause"
+                };
+                if ui.button(label).clicked() {
+                    plots.paused = match plots.paused {
+                        Some(_) => None,
+                        None => Some(history.all.len()),
+                    };
+                }
+                ui.add(
+                    egui::DragValue::new(&mut plots.window)
+                        .clamp_range(1.0..=600.0)
+                        .suffix(" s"),
+                );
+            });
+            ui.horizontal_wrapped(|ui| {
+                for series in Series::ALL {
+                    let mut shown = plots.shown.contains(&series);
+                    if ui.checkbox(&mut shown, series.name()).changed() {
+                        plots.shown.retain(|s| *s != series);
+                        if shown {
+                            plots.shown.push(series);
+                        }
+                    }
+                }
+            });
+
+            // Walk back from the newest sample until the window is full
+            let end = plots
+                .paused
+                .unwrap_or(history.all.len())
+                .min(history.all.len());
+            let mut start = end;
+            let mut span = 0.0;
+            while start > 0 && span < plots.window {
+                start -= 1;
+                span += history.all[start].dt as f64;
+            }
+            let samples = &history.all[start..end];
+            let times: Vec<f64> = samples
+                .iter()
+                .scan(-span, |t, s| {
+                    *t += s.dt as f64;
+                    Some(*t)
+                })
+                .collect();
+            let line = |value: &dyn Fn(&Sample) -> f64| -> PlotPoints {
+                times
+                    .iter()
+                    .zip(samples)
+                    .map(|(t, s)| [*t, value(s)])
+                    .collect()
+            };
+
+            for series in Series::ALL.into_iter().filter(|s| plots.shown.contains(s)) {
+                ui.label(series.name());
+                Plot::new(series.name())
+                    .height(PLOT_HEIGHT)
+                    .legend(Legend::default())
+                    .include_x(-plots.window)
+                    .include_x(0.0)
+                    .show(ui, |plot_ui| match series {
+                        Series::Norm => {
+                            let norm = |s: &Sample| {
+                                let [x, y, z] = kind.calibrated(s, &calibration);
+                                Vec3::new(x, y, z).length() as f64
+                            };
+                            plot_ui.line(Line::new(line(&norm)).name("norm"));
+                            plot_ui.hline(HLine::new(field.f).name("F"));
+                        }
+                        Series::Dt => {
+                            plot_ui.line(Line::new(line(&|s: &Sample| s.dt as f64)).name("dt"));
+                        }
+                        Series::RawMag | Series::CalMag | Series::Accel | Series::Gyro => {
+                            for (i, axis) in AXES.iter().enumerate() {
+                                let value = |s: &Sample| series.axes(s)[i] as f64;
+                                plot_ui.line(Line::new(line(&value)).name(*axis));
+                            }
+                        }
+                    });
+            }
+        });
+}
@@ -0,0 +1,35 @@
+use bevy::prelude::*;
+
+use crate::{Calibration, Sample};
+
+/// Sent for every line or binary frame that was parsed into a [`Sample`]
+pub struct SampleRead {
+    /// Line as received, or the sample as JSON if it came in a binary frame
+    pub line: String,
+    pub sample: Sample,
+}
+
+/// Every sample received so far
+#[derive(Resource, Default)]
+pub struct Samples {
+    pub all: Vec<Sample>,
+}
+
+/// Registers [`SampleRead`], collects read samples into [`Samples`] and
+/// provides the [`Calibration`] resource
+pub struct CalibrationPlugin;
+
+impl Plugin for CalibrationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SampleRead>()
+            .init_resource::<Samples>()
+            .init_resource::<Calibration>()
+            .add_system(collect_samples);
+    }
+}
+
+fn collect_samples(mut ev_samples: EventReader<SampleRead>, mut history: ResMut<Samples>) {
+    history
+        .all
+        .extend(ev_samples.iter().map(|ev| ev.sample.clone()));
+}
@@ -0,0 +1,159 @@
+//! Writes every parsed sample to a newline-delimited JSON session log
+//!
+//! Each line holds the sample fields, so logs can be replayed as is, plus
+//! the line as it was received and the host time it was received at.
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use serde::Serialize;
+
+use bevy_mag::{Sample, SampleRead};
+
+/// Where session logs are written, relative to the working directory
+const RECORDINGS_DIR: &str = "recordings";
+
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Seconds since unix epoch
+    host_time: f64,
+    line: &'a str,
+    #[serde(flatten)]
+    sample: &'a Sample,
+}
+
+struct Session {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    count: usize,
+}
+
+#[derive(Resource, Default)]
+pub struct Recorder {
+    session: Option<Session>,
+    error: Option<String>,
+}
+
+impl Recorder {
+    pub fn is_recording(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Opens a new timestamped log, closing the current one
+    pub fn start(&mut self) {
+        self.stop();
+        let name = chrono::Local::now().format("session-%Y%m%d-%H%M%S%.3f.ndjson");
+        let path = PathBuf::from(RECORDINGS_DIR).join(name.to_string());
+        let opened = std::fs::create_dir_all(RECORDINGS_DIR).and_then(|_| File::create(&path));
+        match opened {
+            Ok(file) => {
+                self.session = Some(Session {
+                    path,
+                    writer: BufWriter::new(file),
+                    count: 0,
+                });
+                self.error = None;
+            }
+            Err(e) => self.error = Some(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(mut session) = self.session.take() {
+            if let Err(e) = session.writer.flush() {
+                self.error = Some(format!("{}: {}", session.path.display(), e));
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str, sample: &Sample) {
+        let Some(session) = self.session.as_mut() else {
+            return;
+        };
+        let host_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let record = Record {
+            host_time,
+            line: line.trim_end(),
+            sample,
+        };
+        let written = serde_json::to_writer(&mut session.writer, &record)
+            .map_err(std::io::Error::from)
+            .and_then(|_| session.writer.write_all(b"\n"));
+        match written {
+            Ok(()) => session.count += 1,
+            Err(e) => {
+                self.error = Some(format!("{}: {}", session.path.display(), e));
+                self.session = None;
+            }
+        }
+    }
+}
+
+pub struct RecorderPlugin {
+    autostart: bool,
+}
+
+impl RecorderPlugin {
+    /// Starts recording right away when `autostart` is set
+    pub fn new(autostart: bool) -> Self {
+        RecorderPlugin { autostart }
+    }
+}
+
+impl Plugin for RecorderPlugin {
+    fn build(&self, app: &mut App) {
+        let mut recorder = Recorder::default();
+        if self.autostart {
+            recorder.start();
+        }
+        app.insert_resource(recorder)
+            .add_system(record_samples)
+            .add_system(draw_recorder_ui);
+    }
+}
+
+fn record_samples(mut recorder: ResMut<Recorder>, mut ev_samples: EventReader<SampleRead>) {
+    let recorder = &mut *recorder;
+    for SampleRead { line, sample } in ev_samples.iter() {
+        recorder.write(line, sample);
+    }
+    // Keep the log usable if the app is killed
+    if let Some(session) = recorder.session.as_mut() {
+        if let Err(e) = session.writer.flush() {
+            recorder.error = Some(e.to_string());
+        }
+    }
+}
+
+fn draw_recorder_ui(mut contexts: EguiContexts, mut recorder: ResMut<Recorder>) {
+    egui::Window::new("Recording").show(contexts.ctx_mut(), |ui| {
+        match &recorder.session {
+            Some(session) => ui.label(format!(
+                "{}: {} samples",
+                session.path.display(),
+                session.count
+            )),
+            None => ui.label("Not recording"),
+        };
+        ui.horizontal(|ui| {
+            if recorder.is_recording() {
+                if ui.button("Stop").clicked() {
+                    recorder.stop();
+                }
+                if ui.button("Split").clicked() {
+                    recorder.start();
+                }
+            } else if ui.button("Start").clicked() {
+                recorder.start();
+            }
+        });
+        if let Some(e) = &recorder.error {
+            ui.colored_label(egui::Color32::RED, e);
+        }
+    });
+}
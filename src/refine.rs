@@ -0,0 +1,280 @@
+//! Levenberg–Marquardt refinement of a calibration
+//!
+//! The ellipsoid fit minimises an algebraic distance. Starting from its result this
+//! minimises the geometric residuals `|a_1 (s - b)| - F` over `b` and the parameters
+//! of `a_1`, which is a linear combination `sum p_i E_i` of the basis of a [`SoftIron`].
+use nalgebra::{DMatrix, DVector, Matrix3, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::math::{FitError, FitModel};
+
+/// Which soft iron matrices are allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SoftIron {
+    /// 1 parameter, the same scale for all axes
+    Scalar,
+    /// 3 parameters, a scale per axis
+    Diagonal,
+    /// 6 parameters, what the ellipsoid fit produces
+    #[default]
+    Symmetric,
+}
+
+impl SoftIron {
+    /// Soft iron with no more parameters than `model` fitted
+    pub fn for_model(model: FitModel) -> Self {
+        match model {
+            FitModel::Offset => SoftIron::Scalar,
+            FitModel::Diagonal => SoftIron::Diagonal,
+            FitModel::Full => SoftIron::Symmetric,
+        }
+    }
+
+    /// Matrices `E_i` such that `a_1 = sum p_i E_i`
+    fn basis(&self) -> Vec<Matrix3<f64>> {
+        let e = |i: usize, j: usize| {
+            let mut m = Matrix3::zeros();
+            m[(i, j)] = 1.0;
+            m
+        };
+        match self {
+            SoftIron::Scalar => vec![Matrix3::identity()],
+            SoftIron::Diagonal => vec![e(0, 0), e(1, 1), e(2, 2)],
+            SoftIron::Symmetric => vec![
+                e(0, 0),
+                e(1, 1),
+                e(2, 2),
+                e(0, 1) + e(1, 0),
+                e(0, 2) + e(2, 0),
+                e(1, 2) + e(2, 1),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RefineOptions {
+    pub soft_iron: SoftIron,
+    pub max_iterations: usize,
+    /// Stop once the cost decreases by less than this fraction
+    pub tolerance: f64,
+}
+
+impl Default for RefineOptions {
+    fn default() -> Self {
+        RefineOptions {
+            soft_iron: SoftIron::Symmetric,
+            max_iterations: 100,
+            tolerance: 1e-10,
+        }
+    }
+}
+
+/// How the refinement went, costs are `sum r^2 / 2`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Refinement {
+    /// Steps taken, each reduced the cost
+    pub iterations: usize,
+    pub initial_cost: f64,
+    pub cost: f64,
+}
+
+struct Model {
+    basis: Vec<Matrix3<f64>>,
+}
+
+impl Model {
+    fn unpack(&self, x: &DVector<f64>) -> (Matrix3<f64>, Vector3<f64>) {
+        let k = self.basis.len();
+        let a_1 = self
+            .basis
+            .iter()
+            .zip(x.iter())
+            .fold(Matrix3::zeros(), |a, (e, p)| a + e * *p);
+        (a_1, Vector3::new(x[k], x[k + 1], x[k + 2]))
+    }
+
+    /// Projects `a_1` onto the basis, which is orthogonal
+    fn pack(&self, a_1: &Matrix3<f64>, b: &Vector3<f64>) -> DVector<f64> {
+        let p = self.basis.iter().map(|e| e.dot(a_1) / e.dot(e));
+        DVector::from_iterator(self.basis.len() + 3, p.chain(b.iter().copied()))
+    }
+
+    fn residuals(&self, s: &[[f64; 3]], x: &DVector<f64>, field: f64) -> DVector<f64> {
+        let (a_1, b) = self.unpack(x);
+        DVector::from_iterator(
+            s.len(),
+            s.iter()
+                .map(|s_j| (a_1 * (Vector3::from(*s_j) - b)).norm() - field),
+        )
+    }
+
+    fn jacobian(&self, s: &[[f64; 3]], x: &DVector<f64>) -> DMatrix<f64> {
+        let (a_1, b) = self.unpack(x);
+        let k = self.basis.len();
+        let mut j = DMatrix::zeros(s.len(), k + 3);
+        for (row, s_j) in s.iter().enumerate() {
+            let u = Vector3::from(*s_j) - b;
+            let v = a_1 * u;
+            let norm = v.norm();
+            if norm == 0.0 {
+                continue;
+            }
+            // d|v| / dv
+            let n = v / norm;
+            for (i, e) in self.basis.iter().enumerate() {
+                j[(row, i)] = n.dot(&(e * u));
+            }
+            let db = -(a_1.transpose() * n);
+            for i in 0..3 {
+                j[(row, k + i)] = db[i];
+            }
+        }
+        j
+    }
+}
+
+fn cost(r: &DVector<f64>) -> f64 {
+    r.norm_squared() / 2.0
+}
+
+/// Minimises `|a_1 (s - b)| - field` starting from `a_1` and `b`
+pub fn refine(
+    s: &[[f64; 3]],
+    a_1: &Matrix3<f64>,
+    b: &Vector3<f64>,
+    field: f64,
+    options: &RefineOptions,
+) -> Result<(Matrix3<f64>, Vector3<f64>, Refinement), FitError> {
+    let model = Model {
+        basis: options.soft_iron.basis(),
+    };
+    let mut x = model.pack(a_1, b);
+    if s.len() < x.len() {
+        return Err(FitError::TooFewSamples {
+            got: s.len(),
+            need: x.len(),
+        });
+    }
+    let mut r = model.residuals(s, &x, field);
+    let initial_cost = cost(&r);
+    let mut lambda = 1e-3;
+    let mut iterations = 0;
+    while iterations < options.max_iterations {
+        let j = model.jacobian(s, &x);
+        let jtj = j.transpose() * &j;
+        let g = j.transpose() * &r;
+        // Marquardt scaling, damp each parameter relative to its curvature
+        let mut damped = jtj.clone();
+        let mut step = None;
+        while lambda < 1e10 {
+            for i in 0..x.len() {
+                damped[(i, i)] = jtj[(i, i)] * (1.0 + lambda) + 1e-12;
+            }
+            if let Some(delta) = damped.clone().cholesky().map(|c| c.solve(&-&g)) {
+                let candidate = &x + delta;
+                let candidate_r = model.residuals(s, &candidate, field);
+                if cost(&candidate_r) < cost(&r) {
+                    step = Some((candidate, candidate_r));
+                    lambda = (lambda / 10.0).max(1e-12);
+                    break;
+                }
+            }
+            lambda *= 10.0;
+        }
+        let Some((candidate, candidate_r)) = step else {
+            // No step reduces the cost, x is a minimum
+            break;
+        };
+        iterations += 1;
+        let decrease = (cost(&r) - cost(&candidate_r)) / cost(&r).max(f64::MIN_POSITIVE);
+        x = candidate;
+        r = candidate_r;
+        if decrease < options.tolerance {
+            break;
+        }
+    }
+    let (a_1, b) = model.unpack(&x);
+    if !a_1.iter().chain(b.iter()).all(|v| v.is_finite()) {
+        return Err(FitError::NotEllipsoid);
+    }
+    let refinement = Refinement {
+        iterations,
+        initial_cost,
+        cost: cost(&r),
+    };
+    Ok((a_1, b, refinement))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::{truth, Noise, FIELD};
+    use crate::math;
+
+    /// Readings of directions over the upper two thirds of the sphere with noise added
+    fn samples(noise_amplitude: f64) -> Vec<[f64; 3]> {
+        let (a_1, b) = truth();
+        let a = a_1.try_inverse().unwrap();
+        let mut noise = Noise::new(3);
+        let n = 400;
+        (0..n)
+            .map(|i| {
+                let z = 1.0 - 1.3 * (i as f64 + 0.5) / n as f64;
+                let r = (1.0 - z * z).sqrt();
+                let phi = 2.4 * i as f64;
+                let u = Vector3::new(r * phi.cos(), r * phi.sin(), z);
+                (a * u * FIELD + b + noise.vector() * noise_amplitude).into()
+            })
+            .collect()
+    }
+
+    fn algebraic_fit(s: &[[f64; 3]]) -> (Matrix3<f64>, Vector3<f64>) {
+        let (m, n, d) = math::ellipsoid_fit(s).unwrap();
+        math::ellipsoid_to_calibration(m, n, d, FIELD).unwrap()
+    }
+
+    #[test]
+    fn refinement_improves_noisy_fit() {
+        let s = samples(20.0);
+        let (a_1, b) = algebraic_fit(&s);
+        let (refined_a_1, refined_b, refinement) =
+            refine(&s, &a_1, &b, FIELD, &RefineOptions::default()).unwrap();
+        assert!(refinement.iterations > 0);
+        assert!(refinement.cost < refinement.initial_cost, "{:?}", refinement);
+
+        // Which fit lands closer to the truth depends on the noise, but the minimum
+        // costs no more than the parameters the readings were generated with
+        let (true_a_1, true_b) = truth();
+        let no_steps = RefineOptions {
+            max_iterations: 0,
+            ..Default::default()
+        };
+        let (_, _, at_truth) = refine(&s, &true_a_1, &true_b, FIELD, &no_steps).unwrap();
+        assert!(refinement.cost <= at_truth.initial_cost);
+        assert!((refined_a_1 - true_a_1).norm() < 0.05);
+        assert!((refined_b - true_b).norm() < 15.0);
+    }
+
+    #[test]
+    fn exact_solution_takes_no_steps() {
+        let (a_1, b) = truth();
+        let (refined_a_1, refined_b, refinement) =
+            refine(&samples(0.0), &a_1, &b, FIELD, &RefineOptions::default()).unwrap();
+        assert_eq!(refinement.iterations, 0);
+        assert!((refined_a_1 - a_1).norm() < 1e-12);
+        assert_eq!(refined_b, b);
+    }
+
+    #[test]
+    fn diagonal_soft_iron_stays_diagonal() {
+        let s = samples(20.0);
+        let (a_1, b) = algebraic_fit(&s);
+        let options = RefineOptions {
+            soft_iron: SoftIron::Diagonal,
+            ..Default::default()
+        };
+        let (refined_a_1, _, _) = refine(&s, &a_1, &b, FIELD, &options).unwrap();
+        assert_eq!(refined_a_1, Matrix3::from_diagonal(&refined_a_1.diagonal()));
+    }
+}
@@ -0,0 +1,222 @@
+//! Replays recorded newline-delimited JSON samples as if they came from the serial port
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use serde::Deserialize;
+
+use crate::serial::SerialReadEvent;
+
+/// Upper bound of lines emitted per frame at [`ReplaySpeed::Max`]
+const MAX_LINES_PER_FRAME: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    RealTime,
+    Fast,
+    Max,
+}
+
+impl ReplaySpeed {
+    fn factor(&self) -> f32 {
+        match self {
+            ReplaySpeed::RealTime => 1.0,
+            ReplaySpeed::Fast => 10.0,
+            ReplaySpeed::Max => f32::INFINITY,
+        }
+    }
+}
+
+/// Only the timing part of a recorded sample
+#[derive(Deserialize)]
+struct Timing {
+    dt: f32,
+}
+
+#[derive(Resource)]
+pub struct Replay {
+    label: String,
+    lines: Vec<String>,
+    next: usize,
+    /// Seconds of recording that are due to be emitted
+    budget: f32,
+    pub speed: ReplaySpeed,
+    pub paused: bool,
+}
+
+impl Replay {
+    pub fn new(label: &str, text: &str) -> Self {
+        Replay {
+            label: label.to_string(),
+            lines: text
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(|l| l.to_string())
+                .collect(),
+            next: 0,
+            budget: 0.0,
+            speed: ReplaySpeed::RealTime,
+            paused: false,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next >= self.lines.len()
+    }
+
+    /// Returns lines that are due after `elapsed` seconds of wall time
+    pub fn advance(&mut self, elapsed: f32) -> Vec<String> {
+        let mut due = vec![];
+        if self.paused {
+            return due;
+        }
+        self.budget += elapsed * self.speed.factor();
+        while !self.finished() && due.len() < MAX_LINES_PER_FRAME {
+            let line = &self.lines[self.next];
+            // Lines without timing are passed through so that the reader rejects them
+            let dt = serde_json::from_str::<Timing>(line)
+                .map(|t| t.dt.max(0.0))
+                .unwrap_or(0.0);
+            if self.speed != ReplaySpeed::Max && dt > self.budget {
+                break;
+            }
+            self.budget -= dt;
+            due.push(line.clone());
+            self.next += 1;
+        }
+        if self.speed == ReplaySpeed::Max || self.finished() {
+            self.budget = 0.0;
+        }
+        due
+    }
+}
+
+pub struct ReplayPlugin {
+    path: String,
+    text: String,
+}
+
+impl ReplayPlugin {
+    /// Replays `text`, the contents of the recorded session at `path`
+    pub fn new(path: &str, text: String) -> Self {
+        ReplayPlugin {
+            path: path.to_string(),
+            text,
+        }
+    }
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SerialReadEvent>()
+            .insert_resource(Replay::new(&self.path, &self.text))
+            .add_system(replay_samples)
+            .add_system(draw_replay_ui);
+    }
+}
+
+fn replay_samples(
+    time: Res<Time>,
+    mut replay: ResMut<Replay>,
+    mut ev_serial: EventWriter<SerialReadEvent>,
+) {
+    let label = replay.label.clone();
+    for line in replay.advance(time.delta_seconds()) {
+        // Lines were split on load, delimit them again for the framing
+        ev_serial.send(SerialReadEvent(
+            label.clone(),
+            format!("{}\n", line).into_bytes(),
+        ));
+    }
+}
+
+fn draw_replay_ui(mut contexts: EguiContexts, mut replay: ResMut<Replay>) {
+    egui::Window::new("Replay").show(contexts.ctx_mut(), |ui| {
+        ui.label(format!(
+            "{}: {} / {}",
+            replay.label,
+            replay.next,
+            replay.lines.len()
+        ));
+        ui.add(egui::ProgressBar::new(
+            replay.next as f32 / replay.lines.len().max(1) as f32,
+        ));
+        ui.horizontal(|ui| {
+            let pause = if replay.paused { "Resume" } else { "Pause" };
+            if ui.button(pause).clicked() {
+                replay.paused = !replay.paused;
+            }
+            ui.radio_value(&mut replay.speed, ReplaySpeed::RealTime, "1x");
+            ui.radio_value(&mut replay.speed, ReplaySpeed::Fast, "10x");
+            ui.radio_value(&mut replay.speed, ReplaySpeed::Max, "Max");
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(lines: usize, dt: f32) -> Replay {
+        let line = format!("{{\"dt\":{}}}\n", dt);
+        Replay::new("test", &line.repeat(lines))
+    }
+
+    #[test]
+    fn emits_lines_as_their_dt_becomes_due() {
+        let mut replay = session(5, 0.25);
+        assert_eq!(replay.advance(0.125).len(), 0);
+        // 0.625 s are due, two lines take 0.5 s
+        assert_eq!(replay.advance(0.5).len(), 2);
+        assert_eq!(replay.advance(0.125).len(), 1);
+        assert_eq!(replay.advance(1.0).len(), 2);
+        assert!(replay.finished());
+        assert_eq!(replay.advance(1.0).len(), 0);
+    }
+
+    #[test]
+    fn pause_holds_lines_and_time() {
+        let mut replay = session(5, 0.25);
+        replay.paused = true;
+        assert_eq!(replay.advance(10.0).len(), 0);
+        replay.paused = false;
+        assert_eq!(replay.advance(0.0).len(), 0);
+        assert_eq!(replay.advance(0.25).len(), 1);
+    }
+
+    #[test]
+    fn speeds() {
+        let mut replay = session(10, 0.25);
+        replay.speed = ReplaySpeed::Fast;
+        assert_eq!(replay.advance(0.05).len(), 2);
+        replay.speed = ReplaySpeed::Max;
+        assert_eq!(replay.advance(0.0).len(), 8);
+        assert!(replay.finished());
+    }
+
+    #[test]
+    fn max_speed_is_capped_per_frame() {
+        let mut replay = session(2 * MAX_LINES_PER_FRAME + 10, 0.01);
+        replay.speed = ReplaySpeed::Max;
+        assert_eq!(replay.advance(0.0).len(), MAX_LINES_PER_FRAME);
+        assert_eq!(replay.advance(0.0).len(), MAX_LINES_PER_FRAME);
+        assert_eq!(replay.advance(0.0).len(), 10);
+        assert!(replay.finished());
+        // Time spent at Max is not owed once back at real time
+        let mut replay = session(10, 0.25);
+        replay.speed = ReplaySpeed::Max;
+        replay.advance(100.0);
+        assert_eq!(replay.budget, 0.0);
+    }
+
+    #[test]
+    fn lines_without_dt_pass_through_at_once() {
+        let text = "not json\n\n{\"raw_mag\":[1,2,3]}\n{\"dt\":0.25}\n{\"dt\":-1}\n";
+        let mut replay = Replay::new("test", text);
+        assert_eq!(
+            replay.advance(0.0),
+            vec!["not json", "{\"raw_mag\":[1,2,3]}"]
+        );
+        // A negative dt counts as zero
+        assert_eq!(replay.advance(0.25), vec!["{\"dt\":0.25}", "{\"dt\":-1}"]);
+        assert!(replay.finished());
+    }
+}
@@ -0,0 +1,453 @@
+//! Serial port the samples come from, opened and closed at runtime
+//!
+//! An open port is read by its own thread, which hands the bytes to [`poll_serial`] over
+//! a channel; closing the port stops that thread and waits for it. Every read becomes a
+//! [`SerialReadEvent`], [`SerialWriteEvent`]s for the open port are written right away.
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::synccell::SyncCell;
+use bevy_egui::{egui, EguiContexts};
+use clap::{Args, ValueEnum};
+use serialport::{SerialPort, SerialPortInfo, SerialPortType};
+
+/// Bytes read from the port named by `.0`
+pub struct SerialReadEvent(pub String, pub Vec<u8>);
+
+/// Bytes to write to the port named by `.0`
+pub struct SerialWriteEvent(pub String, pub Vec<u8>);
+
+/// How long the reader thread blocks before checking whether to stop
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Seconds between attempts to reopen a failed port
+const RECONNECT_AFTER: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+impl From<Parity> for serialport::Parity {
+    fn from(parity: Parity) -> Self {
+        match parity {
+            Parity::None => serialport::Parity::None,
+            Parity::Odd => serialport::Parity::Odd,
+            Parity::Even => serialport::Parity::Even,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FlowControl {
+    None,
+    /// XON/XOFF
+    Software,
+    /// RTS/CTS
+    Hardware,
+}
+
+impl From<FlowControl> for serialport::FlowControl {
+    fn from(flow_control: FlowControl) -> Self {
+        match flow_control {
+            FlowControl::None => serialport::FlowControl::None,
+            FlowControl::Software => serialport::FlowControl::Software,
+            FlowControl::Hardware => serialport::FlowControl::Hardware,
+        }
+    }
+}
+
+/// Line settings of the serial port
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct SerialSettings {
+    #[arg(long, default_value_t = 460800)]
+    pub baud: u32,
+    #[arg(long, value_enum, default_value_t = Parity::None)]
+    pub parity: Parity,
+    #[arg(long, value_enum, default_value_t = FlowControl::None)]
+    pub flow_control: FlowControl,
+    /// 5 to 8
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u8).range(5..=8))]
+    pub data_bits: u8,
+    /// 1 or 2
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub stop_bits: u8,
+}
+
+impl SerialSettings {
+    fn data_bits(&self) -> serialport::DataBits {
+        match self.data_bits {
+            5 => serialport::DataBits::Five,
+            6 => serialport::DataBits::Six,
+            7 => serialport::DataBits::Seven,
+            _ => serialport::DataBits::Eight,
+        }
+    }
+
+    fn stop_bits(&self) -> serialport::StopBits {
+        match self.stop_bits {
+            2 => serialport::StopBits::Two,
+            _ => serialport::StopBits::One,
+        }
+    }
+}
+
+/// An open port and the thread reading it
+struct Link {
+    port: String,
+    writer: SyncCell<Box<dyn SerialPort>>,
+    reader: SyncCell<Receiver<io::Result<Vec<u8>>>>,
+    stop: Arc<AtomicBool>,
+    /// Taken when the link is dropped
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Link {
+    fn open(port: &str, settings: &SerialSettings) -> Result<Self, serialport::Error> {
+        let writer = serialport::new(port, settings.baud)
+            .data_bits(settings.data_bits())
+            .parity(settings.parity.into())
+            .stop_bits(settings.stop_bits())
+            .flow_control(settings.flow_control.into())
+            .timeout(READ_TIMEOUT)
+            .open()?;
+        let mut reader = writer.try_clone()?;
+        let (tx, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            while !stopped.load(Ordering::Relaxed) {
+                match reader.read(&mut buffer) {
+                    Ok(0) => {}
+                    Ok(n) => {
+                        if tx.send(Ok(buffer[..n].to_vec())).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+                        ) => {}
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Link {
+            port: port.to_string(),
+            writer: SyncCell::new(writer),
+            reader: SyncCell::new(rx),
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // Wait for the reader to release the port, so it can be opened again right away.
+        // It notices within READ_TIMEOUT.
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkStatus {
+    Disconnected,
+    Connected,
+    /// Could not open the port or it failed while open
+    Failed(String),
+}
+
+/// Traffic since the port was last opened, a packet is one read or one write
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counters {
+    pub bytes_read: u64,
+    pub packets_read: u64,
+    pub bytes_written: u64,
+    pub packets_written: u64,
+}
+
+#[derive(Resource)]
+pub struct Connection {
+    /// Port to open
+    pub port: String,
+    pub settings: SerialSettings,
+    /// Reopen the port once it failed
+    pub reconnect: bool,
+    link: Option<Link>,
+    pub status: LinkStatus,
+    pub counters: Counters,
+    /// Ports found by the last [`Connection::refresh_ports`]
+    ports: Vec<SerialPortInfo>,
+}
+
+impl Connection {
+    pub fn new(port: &str, settings: SerialSettings) -> Self {
+        let mut connection = Connection {
+            port: port.to_string(),
+            settings,
+            reconnect: true,
+            link: None,
+            status: LinkStatus::Disconnected,
+            counters: Counters::default(),
+            ports: vec![],
+        };
+        connection.refresh_ports();
+        connection
+    }
+
+    /// Opens [`Connection::port`], closing the current one
+    pub fn connect(&mut self) {
+        self.link = None;
+        self.counters = Counters::default();
+        match Link::open(&self.port, &self.settings) {
+            Ok(link) => {
+                self.link = Some(link);
+                self.status = LinkStatus::Connected;
+            }
+            Err(e) => self.status = LinkStatus::Failed(format!("{}: {}", self.port, e)),
+        }
+    }
+
+    pub fn disconnect(&mut self) {
+        self.link = None;
+        self.status = LinkStatus::Disconnected;
+    }
+
+    /// Name of the open port
+    pub fn connected_port(&self) -> Option<&str> {
+        self.link.as_ref().map(|link| link.port.as_str())
+    }
+
+    pub fn refresh_ports(&mut self) {
+        self.ports = serialport::available_ports().unwrap_or_default();
+    }
+
+    fn fail(&mut self, e: impl std::fmt::Display) {
+        self.link = None;
+        self.status = LinkStatus::Failed(format!("{}: {}", self.port, e));
+    }
+}
+
+pub struct SerialConnectionPlugin {
+    port: Option<String>,
+    settings: SerialSettings,
+}
+
+impl SerialConnectionPlugin {
+    /// Connects to `port` at startup if given, otherwise waits for one to be picked
+    pub fn new(port: Option<&str>, settings: SerialSettings) -> Self {
+        SerialConnectionPlugin {
+            port: port.map(|p| p.to_string()),
+            settings,
+        }
+    }
+}
+
+impl Plugin for SerialConnectionPlugin {
+    fn build(&self, app: &mut App) {
+        let mut connection = Connection::new(
+            self.port.as_deref().unwrap_or_default(),
+            self.settings.clone(),
+        );
+        if self.port.is_some() {
+            connection.connect();
+        }
+        app.add_event::<SerialReadEvent>()
+            .add_event::<SerialWriteEvent>()
+            .insert_resource(connection)
+            .add_system(poll_serial)
+            .add_system(write_serial)
+            .add_system(reconnect)
+            .add_system(draw_connection_ui);
+    }
+}
+
+fn poll_serial(mut connection: ResMut<Connection>, mut ev_serial: EventWriter<SerialReadEvent>) {
+    let Some(link) = connection.link.as_mut() else {
+        return;
+    };
+    let port = link.port.clone();
+    let mut read = Counters::default();
+    let mut failure = None;
+    loop {
+        match link.reader.get().try_recv() {
+            Ok(Ok(bytes)) => {
+                read.bytes_read += bytes.len() as u64;
+                read.packets_read += 1;
+                ev_serial.send(SerialReadEvent(port.clone(), bytes));
+            }
+            Ok(Err(e)) => {
+                failure = Some(e.to_string());
+                break;
+            }
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => {
+                failure = Some("reader stopped".to_string());
+                break;
+            }
+        }
+    }
+    connection.counters.bytes_read += read.bytes_read;
+    connection.counters.packets_read += read.packets_read;
+    if let Some(e) = failure {
+        connection.fail(e);
+    }
+}
+
+fn write_serial(mut connection: ResMut<Connection>, mut ev_write: EventReader<SerialWriteEvent>) {
+    for SerialWriteEvent(port, bytes) in ev_write.iter() {
+        let Some(link) = connection.link.as_mut().filter(|link| link.port == *port) else {
+            continue;
+        };
+        match link.writer.get().write_all(bytes) {
+            Ok(()) => {
+                connection.counters.bytes_written += bytes.len() as u64;
+                connection.counters.packets_written += 1;
+            }
+            Err(e) => connection.fail(e),
+        }
+    }
+}
+
+fn reconnect(time: Res<Time>, mut connection: ResMut<Connection>, mut last_attempt: Local<f64>) {
+    if !connection.reconnect || !matches!(connection.status, LinkStatus::Failed(_)) {
+        return;
+    }
+    let now = time.elapsed_seconds_f64();
+    if now - *last_attempt < RECONNECT_AFTER {
+        return;
+    }
+    *last_attempt = now;
+    connection.connect();
+}
+
+fn port_description(info: &SerialPortInfo) -> String {
+    match &info.port_type {
+        SerialPortType::UsbPort(usb) => format!(
+            "{} ({:04x}:{:04x} {})",
+            info.port_name,
+            usb.vid,
+            usb.pid,
+            usb.product.as_deref().unwrap_or("USB")
+        ),
+        SerialPortType::BluetoothPort => format!("{} (Bluetooth)", info.port_name),
+        SerialPortType::PciPort | SerialPortType::Unknown => info.port_name.clone(),
+    }
+}
+
+fn draw_connection_ui(mut contexts: EguiContexts, mut connection: ResMut<Connection>) {
+    egui::Window::new("Connection").show(contexts.ctx_mut(), |ui| {
+        let connection = connection.as_mut();
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source("serial port")
+                .selected_text(connection.port.as_str())
+                .show_ui(ui, |ui| {
+                    for info in &connection.ports {
+                        ui.selectable_value(
+                            &mut connection.port,
+                            info.port_name.clone(),
+                            port_description(info),
+                        );
+                    }
+                });
+            if ui.button("⟳").on_hover_text("Find ports").clicked() {
+                connection.refresh_ports();
+            }
+        });
+        ui.text_edit_singleline(&mut connection.port);
+        egui::Grid::new("serial settings").show(ui, |ui| {
+            let settings = &mut connection.settings;
+            ui.label("Baud");
+            egui::ComboBox::from_id_source("baud")
+                .selected_text(settings.baud.to_string())
+                .show_ui(ui, |ui| {
+                    for baud in [9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600] {
+                        ui.selectable_value(&mut settings.baud, baud, baud.to_string());
+                    }
+                });
+            ui.end_row();
+            ui.label("Parity");
+            ui.horizontal(|ui| {
+                for parity in Parity::value_variants() {
+                    ui.selectable_value(&mut settings.parity, *parity, format!("{:?}", parity));
+                }
+            });
+            ui.end_row();
+            ui.label("Flow control");
+            ui.horizontal(|ui| {
+                for flow_control in FlowControl::value_variants() {
+                    ui.selectable_value(
+                        &mut settings.flow_control,
+                        *flow_control,
+                        format!("{:?}", flow_control),
+                    );
+                }
+            });
+            ui.end_row();
+            ui.label("Data bits");
+            ui.add(egui::DragValue::new(&mut settings.data_bits).clamp_range(5..=8));
+            ui.end_row();
+            ui.label("Stop bits");
+            ui.add(egui::DragValue::new(&mut settings.stop_bits).clamp_range(1..=2));
+            ui.end_row();
+        });
+        ui.horizontal(|ui| {
+            let label = if connection.link.is_some() {
+                "Reconnect"
+            } else {
+                "Connect"
+            };
+            if ui
+                .add_enabled(!connection.port.is_empty(), egui::Button::new(label))
+                .clicked()
+            {
+                connection.connect();
+            }
+            if ui
+                .add_enabled(connection.link.is_some(), egui::Button::new("Disconnect"))
+                .clicked()
+            {
+                connection.disconnect();
+            }
+            ui.checkbox(&mut connection.reconnect, "Reconnect on failure");
+        });
+        match &connection.status {
+            LinkStatus::Disconnected => {
+                ui.label("Disconnected");
+            }
+            LinkStatus::Connected => {
+                ui.colored_label(
+                    egui::Color32::GREEN,
+                    format!(
+                        "Connected to {}",
+                        connection.connected_port().unwrap_or_default()
+                    ),
+                );
+            }
+            LinkStatus::Failed(e) => {
+                ui.colored_label(egui::Color32::RED, e);
+            }
+        }
+        let c = connection.counters;
+        ui.label(format!(
+            "Read {} bytes in {} packets, wrote {} bytes in {} packets",
+            c.bytes_read, c.packets_read, c.bytes_written, c.packets_written
+        ));
+    });
+}
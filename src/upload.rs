@@ -0,0 +1,112 @@
+//! Sends the current calibration to the device and waits for it to be acknowledged
+//!
+//! Once acknowledged the device reports calibrated `cal_mag`, so the view switches
+//! to [`SampleKind::Cal`] to check the result.
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use bevy_mag::command::{self, Reply};
+use bevy_mag::Calibration;
+
+use crate::framing_ui::FrameRead;
+use crate::serial::{Connection, SerialWriteEvent};
+use crate::SampleKind;
+
+/// Seconds to wait for the acknowledgement
+const TIMEOUT: f64 = 2.0;
+
+#[derive(Debug, Default)]
+enum UploadState {
+    #[default]
+    Idle,
+    /// Command sent at the given time
+    Waiting(f64),
+    Done,
+    Failed(String),
+}
+
+#[derive(Resource, Default)]
+struct Upload {
+    state: UploadState,
+}
+
+/// Uploads through the port of the [`Connection`]
+pub struct UploadPlugin;
+
+impl Plugin for UploadPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Upload>()
+            .add_system(wait_for_reply)
+            .add_system(draw_upload_ui);
+    }
+}
+
+fn wait_for_reply(
+    time: Res<Time>,
+    mut upload: ResMut<Upload>,
+    mut kind: ResMut<SampleKind>,
+    mut ev_frames: EventReader<FrameRead>,
+) {
+    let UploadState::Waiting(sent) = upload.state else {
+        ev_frames.clear();
+        return;
+    };
+    for frame in ev_frames.iter() {
+        let FrameRead::Line(line) = frame else {
+            continue;
+        };
+        match command::parse_reply(line, command::CALIBRATION) {
+            Some(Reply::Ack) => {
+                upload.state = UploadState::Done;
+                *kind = SampleKind::Cal;
+                return;
+            }
+            Some(Reply::Nak(reason)) => {
+                upload.state = UploadState::Failed(format!("rejected: {}", reason));
+                return;
+            }
+            None => {}
+        }
+    }
+    if time.elapsed_seconds_f64() - sent > TIMEOUT {
+        upload.state = UploadState::Failed("no acknowledgement".to_string());
+    }
+}
+
+fn draw_upload_ui(
+    mut contexts: EguiContexts,
+    time: Res<Time>,
+    mut upload: ResMut<Upload>,
+    calibration: Res<Calibration>,
+    connection: Res<Connection>,
+    mut ev_write: EventWriter<SerialWriteEvent>,
+) {
+    egui::Window::new("Device").show(contexts.ctx_mut(), |ui| {
+        let waiting = matches!(upload.state, UploadState::Waiting(_));
+        let port = connection.connected_port();
+        if ui
+            .add_enabled(
+                !waiting && port.is_some(),
+                egui::Button::new("Upload to device"),
+            )
+            .clicked()
+        {
+            let line = command::calibration_command(&calibration);
+            let port = port.unwrap_or_default().to_string();
+            ev_write.send(SerialWriteEvent(port, line.into_bytes()));
+            upload.state = UploadState::Waiting(time.elapsed_seconds_f64());
+        }
+        match &upload.state {
+            UploadState::Idle => {}
+            UploadState::Waiting(_) => {
+                ui.label("Waiting for acknowledgement...");
+            }
+            UploadState::Done => {
+                ui.label("Uploaded, showing calibrated samples");
+            }
+            UploadState::Failed(e) => {
+                ui.colored_label(egui::Color32::RED, format!("Upload failed: {}", e));
+            }
+        }
+    });
+}
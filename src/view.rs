@@ -0,0 +1,411 @@
+//! How samples are drawn and what is drawn besides them: wireframes of the fitted
+//! ellipsoid and the target sphere
+//!
+//! The fitted ellipsoid lives in raw sensor coordinates, it is where uncalibrated samples
+//! should lie, `|a_1 (s - b)| = F`. The target sphere of radius `F` is where the
+//! calibration puts them.
+use bevy::prelude::*;
+use bevy::render::mesh::VertexAttributeValues;
+use bevy_egui::{egui, EguiContexts};
+
+use bevy_mag::{Calibration, Sample, Samples};
+
+use crate::{AppState, Field, LineList, LineMaterial, Outliers, RawMeasurements, SampleKind};
+
+/// Circles of latitude and meridians of the wireframes
+const PARALLELS: usize = 8;
+const MERIDIANS: usize = 12;
+/// Straight segments per circle
+const SEGMENTS: usize = 48;
+
+/// Relative residual shown with full color
+const RESIDUAL_RANGE: f32 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Collected before or after "Done"
+    State,
+    /// `|a_1 (s - b)| / F - 1` of the current calibration
+    Residual,
+    /// Order of arrival
+    Time,
+    /// Angle between the accelerometer and its z axis
+    Tilt,
+    /// Rejected by the last robust fit
+    Rejected,
+}
+
+impl ColorMode {
+    const ALL: [ColorMode; 5] = [
+        ColorMode::State,
+        ColorMode::Residual,
+        ColorMode::Time,
+        ColorMode::Tilt,
+        ColorMode::Rejected,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            ColorMode::State => "state",
+            ColorMode::Residual => "residual",
+            ColorMode::Time => "arrival time",
+            ColorMode::Tilt => "tilt",
+            ColorMode::Rejected => "rejection",
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct View {
+    /// Show samples without applying the calibration
+    pub raw_samples: bool,
+    pub ellipsoid: bool,
+    pub sphere: bool,
+    pub color: ColorMode,
+    /// Number of samples collected before "Done"
+    done_at: Option<usize>,
+}
+
+impl Default for View {
+    fn default() -> Self {
+        View {
+            raw_samples: false,
+            ellipsoid: true,
+            sphere: true,
+            color: ColorMode::State,
+            done_at: None,
+        }
+    }
+}
+
+const RED: [f32; 3] = [1.0, 0.0, 0.0];
+const GREEN: [f32; 3] = [0.0, 1.0, 0.0];
+const GREY: [f32; 3] = [0.5, 0.5, 0.5];
+/// Blue, white, red
+const DIVERGING: [[f32; 3]; 3] = [[0.2, 0.3, 1.0], [1.0, 1.0, 1.0], [1.0, 0.2, 0.2]];
+/// Close to viridis
+const SEQUENTIAL: [[f32; 3]; 5] = [
+    [0.27, 0.0, 0.33],
+    [0.23, 0.32, 0.55],
+    [0.13, 0.57, 0.55],
+    [0.37, 0.79, 0.38],
+    [0.99, 0.91, 0.14],
+];
+
+/// Color at `t` from 0 to 1 along evenly spaced `stops`
+fn colormap(stops: &[[f32; 3]], t: f32) -> [f32; 3] {
+    let x = t.clamp(0.0, 1.0) * (stops.len() - 1) as f32;
+    let i = (x as usize).min(stops.len() - 2);
+    let f = x - i as f32;
+    [0, 1, 2].map(|c| stops[i][c] * (1.0 - f) + stops[i + 1][c] * f)
+}
+
+/// Everything the color of a sample depends on
+pub struct Palette<'a> {
+    pub view: &'a View,
+    pub calibration: &'a Calibration,
+    pub field: f32,
+    pub outliers: &'a [usize],
+    /// Number of samples, for arrival time
+    pub count: usize,
+}
+
+impl Palette<'_> {
+    /// Color of the `j`th sample
+    pub fn color(&self, j: usize, sample: &Sample) -> [f32; 4] {
+        let [r, g, b] = match self.view.color {
+            ColorMode::State => match self.view.done_at {
+                Some(done_at) if j >= done_at => GREEN,
+                _ => RED,
+            },
+            ColorMode::Residual if self.calibration.report.is_none() => GREY,
+            ColorMode::Residual => {
+                let [x, y, z] = self.calibration.apply(&sample.raw_mag);
+                let residual = Vec3::new(x, y, z).length() / self.field - 1.0;
+                colormap(&DIVERGING, 0.5 + 0.5 * residual / RESIDUAL_RANGE)
+            }
+            ColorMode::Time => colormap(&SEQUENTIAL, j as f32 / self.count.max(2) as f32),
+            ColorMode::Tilt => {
+                let [x, y, z] = sample.accel;
+                let tilt = Vec3::new(x, y, z).angle_between(Vec3::Z);
+                colormap(&SEQUENTIAL, tilt / std::f32::consts::PI)
+            }
+            ColorMode::Rejected if self.outliers.binary_search(&j).is_ok() => GREY,
+            ColorMode::Rejected => GREEN,
+        };
+        [r, g, b, 1.0]
+    }
+}
+
+impl View {
+    /// Where `sample` is drawn
+    pub fn position(
+        &self,
+        sample: &Sample,
+        calibration: &Calibration,
+        kind: SampleKind,
+    ) -> [f32; 3] {
+        match kind {
+            SampleKind::Raw if self.raw_samples => sample.raw_mag,
+            SampleKind::Raw => calibration.apply(&sample.raw_mag),
+            SampleKind::Cal => sample.cal_mag,
+        }
+    }
+}
+
+#[derive(Component)]
+struct EllipsoidWireframe;
+
+#[derive(Component)]
+struct SphereWireframe;
+
+pub struct ViewPlugin;
+
+impl Plugin for ViewPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<View>()
+            .add_startup_system(spawn_wireframes)
+            .add_system(update_wireframes)
+            .add_system(mark_done)
+            .add_system(recolor_cloud)
+            .add_system(draw_view_ui);
+    }
+}
+
+/// Unit sphere as circles of latitude and meridians
+fn lat_long_sphere() -> LineList {
+    let point =
+        |lat: f32, lon: f32| Vec3::new(lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin());
+    let step = std::f32::consts::TAU / SEGMENTS as f32;
+    let mut lines = vec![];
+    for i in 1..PARALLELS {
+        let lat = std::f32::consts::PI * (i as f32 / PARALLELS as f32 - 0.5);
+        for k in 0..SEGMENTS {
+            let lon = k as f32 * step;
+            lines.push((point(lat, lon), point(lat, lon + step)));
+        }
+    }
+    for i in 0..MERIDIANS {
+        let lon = std::f32::consts::TAU * i as f32 / MERIDIANS as f32;
+        for k in 0..SEGMENTS / 2 {
+            let lat = -std::f32::consts::FRAC_PI_2 + k as f32 * step;
+            lines.push((point(lat, lon), point(lat + step, lon)));
+        }
+    }
+    LineList { lines }
+}
+
+/// Unit sphere with its axes, scaled and rotated onto the principal axes by the transform
+fn ellipsoid_mesh() -> LineList {
+    let mut wireframe = lat_long_sphere();
+    wireframe
+        .lines
+        .extend([Vec3::X, Vec3::Y, Vec3::Z].map(|axis| (-axis, axis)));
+    wireframe
+}
+
+/// Maps the unit sphere onto `b + a_1^-1 (F * unit sphere)`
+///
+/// With `a_1^-1 = U S V^T` the sphere is first turned by `V^T`, which leaves it as is,
+/// so `U` gives the principal axes and `S` their half lengths.
+fn ellipsoid_transform(calibration: &Calibration, field: f32) -> Option<Transform> {
+    let a = calibration.a_1.try_inverse()?.cast::<f32>();
+    let svd = a.svd(true, false);
+    let mut u = svd.u?;
+    if u.determinant() < 0.0 {
+        // A reflection of the unit sphere is the unit sphere
+        u.column_mut(2).neg_mut();
+    }
+    let rotation = Mat3::from_cols_slice(u.as_slice());
+    let s = svd.singular_values * field;
+    Some(Transform {
+        translation: Vec3::from_slice(calibration.b.cast::<f32>().as_slice()),
+        rotation: Quat::from_mat3(&rotation),
+        scale: Vec3::new(s[0], s[1], s[2]),
+    })
+}
+
+fn spawn_wireframes(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut line_materials: ResMut<Assets<LineMaterial>>,
+    field: Res<Field>,
+) {
+    commands.spawn((
+        MaterialMeshBundle {
+            mesh: meshes.add(Mesh::from(ellipsoid_mesh())),
+            material: line_materials.add(LineMaterial {
+                color: Color::ORANGE,
+            }),
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+        EllipsoidWireframe,
+    ));
+    commands.spawn((
+        MaterialMeshBundle {
+            mesh: meshes.add(Mesh::from(lat_long_sphere())),
+            material: line_materials.add(LineMaterial {
+                color: Color::rgb(0.3, 0.3, 0.6),
+            }),
+            transform: Transform::from_scale(Vec3::splat(field.f)),
+            ..default()
+        },
+        SphereWireframe,
+    ));
+}
+
+fn mark_done(state: Res<AppState>, history: Res<Samples>, mut view: ResMut<View>) {
+    if state.is_changed() && *state == AppState::Calibrate && view.done_at.is_none() {
+        view.done_at = Some(history.all.len());
+    }
+}
+
+/// Recolors all samples when anything their color depends on changes
+fn recolor_cloud(
+    view: Res<View>,
+    calibration: Res<Calibration>,
+    field: Res<Field>,
+    outliers: Res<Outliers>,
+    history: Res<Samples>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    query: Query<&Handle<Mesh>, With<RawMeasurements>>,
+) {
+    // Arrival time is relative to the number of samples
+    let changed = view.is_changed()
+        || calibration.is_changed()
+        || field.is_changed()
+        || outliers.is_changed()
+        || (view.color == ColorMode::Time && history.is_changed());
+    if !changed {
+        return;
+    }
+    let palette = Palette {
+        view: &view,
+        calibration: &calibration,
+        field: field.f,
+        outliers: &outliers.0,
+        count: history.all.len(),
+    };
+    for handle in &query {
+        let Some(mesh) = meshes.get_mut(handle) else {
+            continue;
+        };
+        if let Some(VertexAttributeValues::Float32x4(colors)) =
+            mesh.attribute_mut(Mesh::ATTRIBUTE_COLOR)
+        {
+            for (j, (color, sample)) in colors.iter_mut().zip(&history.all).enumerate() {
+                *color = palette.color(j, sample);
+            }
+        }
+    }
+}
+
+type Wireframes<'w, 's, F> = Query<'w, 's, (&'static mut Transform, &'static mut Visibility), F>;
+
+fn update_wireframes(
+    view: Res<View>,
+    calibration: Res<Calibration>,
+    field: Res<Field>,
+    mut ellipsoids: Wireframes<(With<EllipsoidWireframe>, Without<SphereWireframe>)>,
+    mut spheres: Wireframes<With<SphereWireframe>>,
+) {
+    if !(view.is_changed() || calibration.is_changed() || field.is_changed()) {
+        return;
+    }
+    let fitted = calibration
+        .report
+        .and_then(|_| ellipsoid_transform(&calibration, field.f));
+    for (mut transform, mut visibility) in &mut ellipsoids {
+        *visibility = match fitted {
+            Some(fitted) if view.ellipsoid => {
+                *transform = fitted;
+                Visibility::Visible
+            }
+            _ => Visibility::Hidden,
+        };
+    }
+    for (mut transform, mut visibility) in &mut spheres {
+        transform.scale = Vec3::splat(field.f);
+        *visibility = if view.sphere {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+    }
+}
+
+/// Explains the colors of `mode`
+fn legend(ui: &mut egui::Ui, mode: ColorMode) {
+    let swatch = |ui: &mut egui::Ui, [r, g, b]: [f32; 3], text: &str| {
+        ui.horizontal(|ui| {
+            let color = egui::Rgba::from_rgb(r, g, b);
+            ui.colored_label(color, "■");
+            ui.label(text);
+        });
+    };
+    let gradient = |ui: &mut egui::Ui, stops: &[[f32; 3]], from: &str, to: &str| {
+        ui.horizontal(|ui| {
+            ui.label(from);
+            let (rect, _) = ui.allocate_exact_size(egui::vec2(120.0, 12.0), egui::Sense::hover());
+            const STEPS: usize = 24;
+            for k in 0..STEPS {
+                let [r, g, b] = colormap(stops, (k as f32 + 0.5) / STEPS as f32);
+                let x = |k: usize| rect.left() + rect.width() * k as f32 / STEPS as f32;
+                let cell = egui::Rect::from_x_y_ranges(x(k)..=x(k + 1), rect.y_range());
+                ui.painter()
+                    .rect_filled(cell, 0.0, egui::Rgba::from_rgb(r, g, b));
+            }
+            ui.label(to);
+        });
+    };
+    match mode {
+        ColorMode::State => {
+            swatch(ui, RED, "collecting");
+            swatch(ui, GREEN, "after Done");
+        }
+        ColorMode::Residual => {
+            let range = format!("{:.0}%", RESIDUAL_RANGE * 100.0);
+            gradient(
+                ui,
+                &DIVERGING,
+                &format!("-{}", range),
+                &format!("+{} of F", range),
+            );
+            swatch(ui, GREY, "no fit yet");
+        }
+        ColorMode::Time => gradient(ui, &SEQUENTIAL, "first", "last"),
+        ColorMode::Tilt => gradient(ui, &SEQUENTIAL, "0°", "180° from z up"),
+        ColorMode::Rejected => {
+            swatch(ui, GREEN, "kept");
+            swatch(ui, GREY, "rejected as outlier");
+        }
+    }
+}
+
+fn draw_view_ui(mut contexts: EguiContexts, mut view: ResMut<View>) {
+    egui::Window::new("View").show(contexts.ctx_mut(), |ui| {
+        // Changing the view redraws all samples, only flag real changes
+        let v = view.bypass_change_detection();
+        let mut changed = [
+            ui.checkbox(&mut v.raw_samples, "Uncalibrated samples"),
+            ui.checkbox(&mut v.ellipsoid, "Fitted ellipsoid"),
+            ui.checkbox(&mut v.sphere, "Target sphere"),
+        ]
+        .iter()
+        .any(|r| r.changed());
+        let color = v.color;
+        egui::ComboBox::from_label("Color by")
+            .selected_text(v.color.name())
+            .show_ui(ui, |ui| {
+                for mode in ColorMode::ALL {
+                    ui.selectable_value(&mut v.color, mode, mode.name());
+                }
+            });
+        changed |= color != v.color;
+        legend(ui, v.color);
+        if changed {
+            view.set_changed();
+        }
+    });
+}